@@ -1,17 +1,34 @@
-#![feature(fs_try_exists)]
-
-use std::{env::current_dir, fmt::Debug, io::stdout, process::exit};
+use std::{
+    collections::BTreeMap,
+    env::{args_os, current_dir, var, var_os},
+    ffi::OsString,
+    fmt::Debug,
+    io::stdout,
+    path::{Component, Path, PathBuf},
+    process::exit,
+    time::{Duration, SystemTime},
+};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use lib::{
-    directory::{Directory, Ignores},
-    dot_rev::{DotRev, InsertJson},
+    config::{Config, ConfigFile, GC_GRACE_PERIOD, INIT_DEFAULT_BRANCH, USER_EMAIL, USER_NAME},
+    dot_rev::{CheckoutMode, DotRev, Error, MergeOutcome},
+    fsck, gc,
+    history::{log, previous_versions, Graph, LogOptions, Order},
+    ignore::Layers,
     object_id::ObjectId,
-    snapshot::SnapShot,
+    object_store::ObjectStore,
+    snapshot::{Signature, Timestamp},
 };
 
 #[derive(Parser, Debug)]
 struct Arguments {
+    #[arg(
+        long,
+        global = true,
+        help = "the .rev directory to use, REV_DIR or found by searching up from here by default"
+    )]
+    repo: Option<PathBuf>,
     #[clap(subcommand)]
     cmd: Command,
 }
@@ -30,90 +47,606 @@ enum Command {
     Snap {
         #[arg(short, long, help = "message to leave with this snapshot")]
         message: String,
+        #[arg(
+            long,
+            value_name = "KEY=VALUE",
+            value_parser = parse_trailer,
+            help = "key/value pair to attach to this snapshot"
+        )]
+        trailer: Vec<(String, String)>,
     },
     #[clap(about = "switch to branch")]
     Checkout {
         #[arg(short, long, help = "branch to checkout")]
         branch: String,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "carry",
+            help = "discard changes made since the latest snap"
+        )]
+        force: bool,
+        #[arg(
+            short,
+            long,
+            help = "bring changes made since the latest snap over to the branch"
+        )]
+        carry: bool,
     },
     #[clap(about = "print out current branch")]
     Branch,
+    #[clap(about = "show the history of snapshots leading up to a branch or snapshot")]
+    Log {
+        #[arg(help = "branch or snapshot to start from, the current branch by default")]
+        rev: Option<String>,
+        #[arg(short = 'n', long, help = "show at most this many snapshots")]
+        limit: Option<usize>,
+        #[arg(long, help = "only follow the first previous snapshot of merges")]
+        first_parent: bool,
+        #[arg(
+            long,
+            help = "show newer snapshots first rather than keeping lines together"
+        )]
+        date_order: bool,
+        #[arg(long, conflicts_with = "paths", help = "draw the history as a graph")]
+        graph: bool,
+        #[arg(last = true, help = "only show snapshots which changed these paths")]
+        paths: Vec<PathBuf>,
+    },
+    #[clap(about = "get, set and list configuration")]
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    #[clap(about = "merge another branch into this one")]
+    Merge {
+        #[arg(help = "branch to merge")]
+        branch: String,
+        #[arg(short, long, help = "message to leave with the merge snapshot")]
+        message: Option<String>,
+    },
+    #[clap(about = "move loose objects into a pack")]
+    Repack,
+    #[clap(about = "rewrite loose objects with the configured store.compression")]
+    Recompress,
+    #[clap(about = "rewrite snapshots whose directories were stored nested in one object")]
+    Migrate,
+    #[clap(about = "check every object and everything reachable from the branches")]
+    Fsck,
+    #[clap(about = "remove objects which no branch refers to")]
+    Gc {
+        #[arg(
+            long,
+            help = "only remove objects older than this many days, gc.grace-period or 14 by default"
+        )]
+        grace_period: Option<u64>,
+    },
+    #[clap(about = "show which ignore pattern, if any, leaves a path out of snapshots")]
+    CheckIgnore {
+        #[arg(help = "path to check, which need not exist")]
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    #[clap(about = "print the value of a key")]
+    Get { key: String },
+    #[clap(about = "set a key in the repository's configuration")]
+    Set {
+        key: String,
+        value: String,
+        #[arg(long, help = "set it in the user's configuration instead")]
+        user: bool,
+    },
+    #[clap(about = "remove a key from the repository's configuration")]
+    Unset {
+        key: String,
+        #[arg(long, help = "remove it from the user's configuration instead")]
+        user: bool,
+    },
+    #[clap(about = "print every key and its value")]
+    List,
 }
 
 fn main() {
     env_logger::init();
-    let args = Arguments::parse();
+    // Aliases are expanded before the arguments are parsed, so they come
+    // from the repository given by REV_DIR or found from here, not --repo.
+    let aliases = load_config(None);
+    let args = Arguments::parse_from(expand_alias(&aliases, args_os().collect()));
+    let repo: Option<PathBuf> = args.repo;
+    let config = load_config(repo.as_deref());
     use Command::*;
     match args.cmd {
         Diff { branch } => {
-            let dir = current_dir().unwrap();
-            let rev_dir = dir.join(".rev");
-            let dot_rev = DotRev::existing(rev_dir).unwrap();
-            let mut store = dot_rev.store().unwrap();
-            let that_branch = branch;
-            let this_branch: String = dot_rev.branch().unwrap();
-            if !dot_rev.branch_exists(&that_branch).unwrap() {
-                eprintln!("no branch named {} exists", that_branch);
-                exit(1);
+            let dot_rev = open(repo.as_deref());
+            match dot_rev.diff(&branch) {
+                Ok(diff) => println!("{diff}"),
+                Err(err) => fail(err),
             }
-            let this_tip: ObjectId = dot_rev.branch_snapshot_id(&this_branch).unwrap();
-            let that_tip: ObjectId = dot_rev.branch_snapshot_id(&that_branch).unwrap();
-            let that_snapshot: SnapShot = store.read_json(that_tip).expect("read that tip");
-            let that_branch_directory = store
-                .read_json(that_snapshot.directory)
-                .expect("read that directory");
-            let this_snapshot: SnapShot = store.read_json(this_tip).expect("read this tip");
-            let this_branch_directory: Directory = store
-                .read_json(this_snapshot.directory)
-                .expect("read this branch directory");
-            let diff = &this_branch_directory.diff(&that_branch_directory);
-            println!("{diff}")
         }
         Branch => {
-            let dot_rev = DotRev::existing(current_dir().unwrap().join(".rev")).unwrap();
-            let branch = dot_rev.branch().unwrap();
+            let dot_rev = open(repo.as_deref());
+            let branch = dot_rev.branch().unwrap_or_else(|err| fail(err));
             println!("{}", branch);
         }
-        Checkout { branch } => {
-            let dot_rev = DotRev::existing(current_dir().unwrap().join(".rev")).unwrap();
-            if !dot_rev.branch_exists(&branch).unwrap() {
-                dot_rev.create_branch(&branch).unwrap();
+        Checkout {
+            branch,
+            force,
+            carry,
+        } => {
+            let mut dot_rev = open(repo.as_deref());
+            let mode = if force {
+                CheckoutMode::Force
+            } else if carry {
+                CheckoutMode::Carry
+            } else {
+                CheckoutMode::Clean
+            };
+            match dot_rev.checkout(&branch, mode) {
+                Ok(()) => {}
+                Err(Error::MergeInProgress) => {
+                    eprintln!("a merge is in progress, snap it or use --force to abandon it");
+                    exit(1);
+                }
+                Err(Error::Conflicts(conflicts)) => {
+                    eprintln!("the work tree conflicts with {}:", branch);
+                    for (path, conflict) in conflicts {
+                        eprintln!("C {} ({})", path.display(), conflict);
+                    }
+                    exit(1);
+                }
+                Err(Error::UnsnappedChanges(diff)) => {
+                    eprintln!("changes since the latest snap would be lost:");
+                    eprint!("{diff}");
+                    eprintln!("snap them, or use --carry or --force");
+                    exit(1);
+                }
+                Err(err) => fail(err),
             }
-            dot_rev.set_branch(&branch).unwrap();
         }
-        Changes => {
-            let dir = current_dir().unwrap();
-            let rev_dir = dir.join(".rev");
-            let dot_rev = DotRev::existing(rev_dir).unwrap();
-            let mut store = dot_rev.store().unwrap();
-            let branch: String = dot_rev.branch().unwrap();
-            let old_tip: ObjectId = dot_rev.branch_snapshot_id(&branch).unwrap();
-            let ignores: Ignores = dot_rev.ignores().unwrap();
-            let directory = Directory::new(dir.as_path(), &ignores, &mut store).unwrap();
-            let snapshot: SnapShot = store.read_json(old_tip).unwrap();
-            let old_directory: Directory = store.read_json(snapshot.directory).unwrap();
-            serde_json::to_writer_pretty(stdout(), &old_directory.diff(&directory)).unwrap();
-        }
-        Snap { message } => {
-            let dir = current_dir().unwrap();
-            let rev_dir = dir.join(".rev");
-            let dot_rev = DotRev::existing(rev_dir).unwrap();
-            let mut store = dot_rev.store().unwrap();
-            let branch: String = dot_rev.branch().unwrap();
-            let old_tip: ObjectId = dot_rev.branch_snapshot_id(&branch).unwrap();
-            let ignores: Ignores = dot_rev.ignores().unwrap();
-            let directory = Directory::new(dir.as_path(), &ignores, &mut store).unwrap();
-            let directory_id = store.insert_json(&directory).unwrap();
-            let snap = SnapShot {
-                directory: directory_id,
-                previous: vec![old_tip].into_iter().collect(),
-                message,
+        Log {
+            rev,
+            limit,
+            first_parent,
+            date_order,
+            graph,
+            paths,
+        } => {
+            let dot_rev = open(repo.as_deref());
+            let tip: ObjectId = match rev {
+                Some(rev) => match dot_rev.resolve(&rev) {
+                    Ok(tip) => tip,
+                    Err(_) => {
+                        eprintln!("no branch or snapshot named {} exists", rev);
+                        exit(1);
+                    }
+                },
+                None => dot_rev
+                    .current_snapshot_id()
+                    .unwrap_or_else(|err| fail(err)),
+            };
+            let options = LogOptions {
+                order: if date_order {
+                    Order::Date
+                } else {
+                    Order::Topological
+                },
+                first_parent,
+                limit,
+                paths: paths
+                    .iter()
+                    .map(|path| work_tree_path(&dot_rev, path))
+                    .collect(),
             };
-            let snap_id = store.insert_json(&snap).unwrap();
-            dot_rev.set_branch_snapshot_id(&branch, snap_id).unwrap();
+            let mut drawing = Graph::new();
+            for (id, snapshot) in log(dot_rev.store(), &[tip], &options).unwrap() {
+                let summary = snapshot.message.lines().next().unwrap_or("");
+                if graph {
+                    let previous = if first_parent {
+                        &snapshot.previous[..snapshot.previous.len().min(1)]
+                    } else {
+                        &snapshot.previous[..]
+                    };
+                    let lines = drawing.next(id, previous);
+                    for line in lines.before {
+                        println!("{}", line);
+                    }
+                    println!("{}{} {}", lines.snapshot, id, summary);
+                    for line in lines.after {
+                        println!("{}", line);
+                    }
+                } else {
+                    println!("{} {}", id, summary);
+                }
+            }
+        }
+        Merge { branch, message } => {
+            let mut dot_rev = open(repo.as_deref());
+            let this_branch = dot_rev.branch().unwrap_or_else(|err| fail(err));
+            let author = signature(&config, "AUTHOR");
+            let committer = signature(&config, "COMMITTER");
+            match dot_rev.merge(&branch, message, author, committer) {
+                Ok(MergeOutcome::AlreadyMerged) => {
+                    println!("{} is already merged into {}", branch, this_branch);
+                }
+                Ok(MergeOutcome::FastForward(tip)) => {
+                    println!("fast-forwarded {} to {}", this_branch, tip);
+                }
+                Ok(MergeOutcome::Merged(snap_id)) => println!("{}", snap_id),
+                Ok(MergeOutcome::Conflicts(conflicts)) => {
+                    eprintln!("conflicts merging {}:", branch);
+                    for (path, conflict) in conflicts {
+                        eprintln!("C {} ({})", path.display(), conflict);
+                    }
+                    eprintln!("resolve them and snap to finish the merge");
+                    exit(1);
+                }
+                Err(Error::MergeInProgress) => {
+                    eprintln!("a merge is already in progress, resolve it and snap first");
+                    exit(1);
+                }
+                Err(Error::UnsnappedChanges(diff)) => {
+                    eprintln!("changes since the latest snap would be lost:");
+                    eprint!("{diff}");
+                    exit(1);
+                }
+                Err(err) => fail(err),
+            }
+        }
+        Changes => {
+            let mut dot_rev = open(repo.as_deref());
+            let diff = dot_rev.changes().unwrap_or_else(|err| fail(err));
+            serde_json::to_writer_pretty(stdout(), &diff).unwrap();
+        }
+        Snap { message, trailer } => {
+            let mut dot_rev = open(repo.as_deref());
+            dot_rev
+                .snap(
+                    message,
+                    signature(&config, "AUTHOR"),
+                    signature(&config, "COMMITTER"),
+                    trailer.into_iter().collect(),
+                )
+                .unwrap_or_else(|err| fail(err));
         }
         Init => {
-            DotRev::init(current_dir().unwrap().join(".rev")).unwrap();
+            let default_branch = config
+                .get(INIT_DEFAULT_BRANCH)
+                .unwrap_or_else(|| String::from("dev"));
+            let rev_dir = repo_dir(repo.as_deref()).unwrap_or(current_dir().unwrap().join(".rev"));
+            DotRev::init(rev_dir, &default_branch).unwrap_or_else(|err| fail(err));
+        }
+        Repack => {
+            let mut dot_rev = open(repo.as_deref());
+            let mut tips = Vec::new();
+            for branch in dot_rev.branches().unwrap_or_else(|err| fail(err)) {
+                tips.push(
+                    dot_rev
+                        .branch_snapshot_id(&branch)
+                        .unwrap_or_else(|err| fail(err)),
+                );
+            }
+            tips.extend(dot_rev.merge_snapshot_id().unwrap_or_else(|err| fail(err)));
+            let versions =
+                previous_versions(dot_rev.store(), tips).unwrap_or_else(|err| fail(err.into()));
+            let store = dot_rev.store_mut();
+            if store.packs().is_none() {
+                eprintln!("store.backend is loose, so there are no packs to move objects into");
+                exit(1);
+            }
+            let ids: Vec<ObjectId> = store
+                .loose()
+                .ids()
+                .collect::<Result<_, _>>()
+                .unwrap_or_else(|err| fail(err.into()));
+            for &id in &ids {
+                let object = match store.loose().read(id) {
+                    Ok(Some(object)) => object,
+                    Ok(None) => fail(Error::MissingObject(id)),
+                    Err(err) => fail(err.into()),
+                };
+                let packs = store.packs().unwrap();
+                packs.insert(&object).unwrap_or_else(|err| fail(err.into()));
+                if let Some(&base) = versions.get(&id) {
+                    packs.suggest_base(id, base);
+                }
+            }
+            // Only remove the loose objects once the pack holding them has
+            // been written.
+            if let Some(packs) = store.packs() {
+                packs.flush().unwrap_or_else(|err| fail(err.into()));
+            }
+            for &id in &ids {
+                store
+                    .loose()
+                    .remove(id)
+                    .unwrap_or_else(|err| fail(err.into()));
+            }
+            eprintln!("packed {} objects", ids.len());
+        }
+        Recompress => {
+            let mut dot_rev = open(repo.as_deref());
+            let store = dot_rev.store_mut().loose();
+            let mut changed = 0;
+            let ids: Vec<ObjectId> = store
+                .ids()
+                .collect::<Result<_, _>>()
+                .unwrap_or_else(|err| fail(err.into()));
+            for id in ids {
+                if store.recompress(id).unwrap_or_else(|err| fail(err.into())) {
+                    changed += 1;
+                }
+            }
+            eprintln!("rewrote {} objects", changed);
+        }
+        Migrate => {
+            let mut dot_rev = open(repo.as_deref());
+            let migrated = dot_rev.migrate().unwrap_or_else(|err| fail(err));
+            eprintln!("rewrote {} snapshots", migrated);
+        }
+        Fsck => {
+            let dot_rev = open(repo.as_deref());
+            let ids: Vec<ObjectId> = dot_rev
+                .store()
+                .ids()
+                .collect::<Result<_, _>>()
+                .unwrap_or_else(|err| fail(err.into()));
+            let mut refs = BTreeMap::new();
+            for branch in dot_rev.branches().unwrap_or_else(|err| fail(err)) {
+                let id = dot_rev.branch_snapshot_id(&branch).ok();
+                refs.insert(branch, id);
+            }
+            if let Ok(Some(id)) = dot_rev.merge_snapshot_id() {
+                refs.insert(String::from("merge"), Some(id));
+            }
+            let report = fsck::check(dot_rev.store(), ids, &refs);
+            for id in &report.missing {
+                println!("missing {}", id);
+            }
+            for id in &report.corrupt {
+                println!("corrupt {}", id);
+            }
+            for id in &report.dangling {
+                println!("dangling {}", id);
+            }
+            for (name, reason) in &report.bad_refs {
+                println!("bad branch {}: {}", name, reason);
+            }
+            if !report.is_ok() {
+                exit(1);
+            }
+        }
+        Gc { grace_period } => {
+            let mut dot_rev = open(repo.as_deref());
+            let (key, grace_period) = match grace_period {
+                Some(days) => ("--grace-period", days),
+                None => (
+                    GC_GRACE_PERIOD,
+                    config
+                        .get_parsed(GC_GRACE_PERIOD)
+                        .unwrap_or_else(|err| fail(err))
+                        .unwrap_or(14),
+                ),
+            };
+            let cutoff = grace_period
+                .checked_mul(24 * 60 * 60)
+                .and_then(|seconds| SystemTime::now().checked_sub(Duration::from_secs(seconds)))
+                .unwrap_or_else(|| {
+                    fail(Error::BadConfig(
+                        String::from(key),
+                        grace_period.to_string(),
+                    ))
+                });
+            let mut roots = Vec::new();
+            for branch in dot_rev.branches().unwrap_or_else(|err| fail(err)) {
+                roots.push(
+                    dot_rev
+                        .branch_snapshot_id(&branch)
+                        .unwrap_or_else(|err| fail(err)),
+                );
+            }
+            roots.extend(dot_rev.merge_snapshot_id().unwrap_or_else(|err| fail(err)));
+            let live = match gc::mark(dot_rev.store(), roots) {
+                Ok(live) => live,
+                Err(err) => {
+                    eprintln!("not collecting garbage, run revtool fsck: {:?}", err);
+                    exit(1);
+                }
+            };
+            let store = dot_rev.store_mut();
+            let removed = gc::sweep(store, &live, cutoff).unwrap_or_else(|err| fail(err.into()));
+            if let Some(packs) = store.packs() {
+                packs.flush().unwrap_or_else(|err| fail(err.into()));
+            }
+            eprintln!("removed {} objects", removed.len());
+        }
+        CheckIgnore { path } => {
+            let dot_rev = open(repo.as_deref());
+            let relative = work_tree_path(&dot_rev, &path);
+            let mut layers = Layers::new(dot_rev.ignores().unwrap_or_else(|err| fail(err)).rules());
+            layers
+                .read_down_to(
+                    dot_rev.work_tree(),
+                    relative.parent().unwrap_or(Path::new("")),
+                )
+                .unwrap_or_else(|err| fail(err.into()));
+            let is_dir = path.is_dir() || path.to_string_lossy().ends_with('/');
+            match layers.explain(&relative, is_dir) {
+                Some(found) if !found.pattern.is_negated() => {
+                    println!("{}\t{}", found, path.display());
+                }
+                Some(found) => {
+                    println!("{}\t{}", found, path.display());
+                    eprintln!("{} is not ignored", path.display());
+                    exit(1);
+                }
+                None => {
+                    eprintln!("{} is not ignored", path.display());
+                    exit(1);
+                }
+            }
+        }
+        Config { action } => match action {
+            ConfigAction::Get { key } => match config.get(&key) {
+                Some(value) => println!("{}", value),
+                None => exit(1),
+            },
+            ConfigAction::List => {
+                for (key, value) in config.list() {
+                    println!("{}={}", key, value);
+                }
+            }
+            ConfigAction::Set { key, value, user } => {
+                config_file(repo.as_deref(), user)
+                    .set(&key, &value)
+                    .unwrap();
+            }
+            ConfigAction::Unset { key, user } => {
+                config_file(repo.as_deref(), user).unset(&key).unwrap();
+            }
+        },
+    }
+}
+
+/// The identity to record in the given role, `AUTHOR` or `COMMITTER`, read
+/// from `REV_<ROLE>_NAME`, `REV_<ROLE>_EMAIL` and `REV_<ROLE>_DATE`. The
+/// name and email fall back to `user.name` and `user.email` from the
+/// configuration, the name then to the login name in `USER`, and the date
+/// to now.
+fn signature(config: &Config, role: &str) -> Option<Signature> {
+    let name = var(format!("REV_{}_NAME", role))
+        .ok()
+        .or_else(|| config.get(USER_NAME))
+        .or_else(|| var("USER").ok())?;
+    let email = var(format!("REV_{}_EMAIL", role))
+        .ok()
+        .or_else(|| config.get(USER_EMAIL))
+        .unwrap_or_default();
+    let time = match var(format!("REV_{}_DATE", role)) {
+        Ok(date) => date.parse().unwrap_or_else(|err| {
+            eprintln!("bad REV_{}_DATE: {}", role, err);
+            exit(1);
+        }),
+        Err(_) => Timestamp::now(),
+    };
+    Some(Signature { name, email, time })
+}
+
+fn parse_trailer(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) => Ok((String::from(key), String::from(value))),
+        None => Err(format!("expected KEY=VALUE, got '{}'", s)),
+    }
+}
+
+/// Replace the subcommand with its expansion if it is an alias from the
+/// configuration rather than a built in command.
+fn expand_alias(config: &Config, mut args: Vec<OsString>) -> Vec<OsString> {
+    let Some(name) = args.get(1).and_then(|arg| arg.to_str()) else {
+        return args;
+    };
+    if Arguments::command().find_subcommand(name).is_some() {
+        return args;
+    }
+    if let Some(expansion) = config.alias(name) {
+        args.splice(1..2, expansion.into_iter().map(OsString::from));
+    }
+    args
+}
+
+/// The layered configuration, including that of the repository given by
+/// [`repo_dir`] or found from here, if there is one.
+fn load_config(repo: Option<&Path>) -> Config {
+    let rev_dir = repo_dir(repo).or_else(|| DotRev::discover_root(&current_dir().unwrap()).ok());
+    Config::load(rev_dir.as_deref()).unwrap_or_else(|err| {
+        eprintln!("could not read the configuration: {:?}", err);
+        exit(1)
+    })
+}
+
+/// The configuration file `config set` and `config unset` change.
+fn config_file(repo: Option<&Path>, user: bool) -> ConfigFile {
+    if user {
+        match ConfigFile::user().unwrap() {
+            Some(file) => file,
+            None => {
+                eprintln!("no user configuration file, set REV_USER_CONFIG or HOME");
+                exit(1);
+            }
+        }
+    } else {
+        ConfigFile::repository(&rev_root(repo)).unwrap()
+    }
+}
+
+/// Report an error which is the user's to fix, such as naming a branch
+/// which does not exist, and exit.
+fn fail(err: Error) -> ! {
+    match err {
+        Error::NoBranch(branch) => eprintln!("no branch named {} exists", branch),
+        Error::BadConfig(key, value) => eprintln!("{} is not a valid value for {}", value, key),
+        Error::BadName(name) => eprintln!("refusing to write the entry named {:?}", name),
+        Error::Conflicts(conflicts) => {
+            eprintln!("the work tree is in the way of writing out the snapshot:");
+            for (path, conflict) in conflicts {
+                eprintln!("C {} ({})", path.display(), conflict);
+            }
+        }
+        err => eprintln!("{:?}", err),
+    }
+    exit(1)
+}
+
+/// The .rev directory given with `--repo` or the `REV_DIR` environment
+/// variable, if any.
+fn repo_dir(repo: Option<&Path>) -> Option<PathBuf> {
+    repo.map(PathBuf::from)
+        .or_else(|| var_os("REV_DIR").map(PathBuf::from))
+}
+
+/// The .rev directory given by [`repo_dir`], or else the one found by
+/// searching up from the current directory.
+fn rev_root(repo: Option<&Path>) -> PathBuf {
+    let root = match repo_dir(repo) {
+        Some(rev_dir) => std::fs::read_dir(&rev_dir)
+            .map(|_| rev_dir)
+            .map_err(Error::IO),
+        None => DotRev::discover_root(&current_dir().unwrap()),
+    };
+    match root {
+        Ok(root) => root,
+        Err(err) => {
+            eprintln!("not in a revision, run revtool init first: {:?}", err);
+            exit(1);
+        }
+    }
+}
+
+/// Open the .rev directory given by [`rev_root`].
+fn open(repo: Option<&Path>) -> DotRev {
+    DotRev::existing(rev_root(repo)).unwrap_or_else(|err| fail(err))
+}
+
+/// The path relative to the work tree of a path given relative to the
+/// current directory.
+fn work_tree_path(dot_rev: &DotRev, path: &Path) -> PathBuf {
+    let mut absolute = PathBuf::new();
+    for component in current_dir().unwrap().join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    match absolute.strip_prefix(dot_rev.work_tree()) {
+        Ok(path) => path.to_path_buf(),
+        Err(_) => {
+            eprintln!("{} is outside of the revision", path.display());
+            exit(1);
         }
     }
 }
@@ -0,0 +1,106 @@
+use std::{
+    fs::{remove_file, rename, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A file which is written under a temporary name in the directory it
+/// will end up in, and only appears under its real name once it is
+/// complete and on disk. If it is dropped before then, it is removed.
+///
+/// Temporary names start with a `.`, so that code listing the directory
+/// can tell them apart.
+pub(crate) struct AtomicFile {
+    temporary: PathBuf,
+    file: Option<File>,
+}
+
+impl AtomicFile {
+    /// Start writing a file which will be moved into the given directory.
+    pub(crate) fn new(dir: &Path) -> Result<Self, std::io::Error> {
+        let temporary = dir.join(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .create_new(true)
+            .write(true)
+            .open(&temporary)?;
+        Ok(Self {
+            temporary,
+            file: Some(file),
+        })
+    }
+
+    /// Flush the contents to disk and move the file to `path`, replacing
+    /// whatever was there before.
+    pub(crate) fn commit(mut self, path: &Path) -> Result<(), std::io::Error> {
+        let file = self.file.take().unwrap();
+        file.sync_all()?;
+        drop(file);
+        rename(&self.temporary, path)?;
+        sync_dir(path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = remove_file(&self.temporary);
+        }
+    }
+}
+
+/// Replace the file at `path` with the contents, so that readers see
+/// either the old contents or the new ones but never a partial write.
+pub(crate) fn write(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = AtomicFile::new(dir)?;
+    file.write_all(contents)?;
+    file.commit(path)
+}
+
+/// Make a rename into the directory of `path` durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), std::io::Error> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+#[test]
+fn test_atomic_write() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("branch");
+    write(&path, b"a much longer value").unwrap();
+    write(&path, b"short").unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"short");
+    let mut abandoned = AtomicFile::new(tempdir.path()).unwrap();
+    abandoned.write_all(b"never committed").unwrap();
+    drop(abandoned);
+    let names: Vec<_> = std::fs::read_dir(tempdir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, vec!["branch"]);
+}
@@ -0,0 +1,145 @@
+use std::io::Read;
+
+/// No chunk is smaller than this, apart from the last one of a file.
+pub const MIN_SIZE: usize = 16 * 1024;
+/// The size chunks are cut at on average.
+pub const AVERAGE_SIZE: usize = 64 * 1024;
+/// No chunk is larger than this.
+pub const MAX_SIZE: usize = 256 * 1024;
+
+/// Before the average size, cuts need more of the hash to be zero, and
+/// after it fewer, which keeps chunk sizes close to the average.
+const MASK_SMALL: u64 = !0 << (64 - (AVERAGE_SIZE.ilog2() + 2));
+const MASK_LARGE: u64 = !0 << (64 - (AVERAGE_SIZE.ilog2() - 2));
+
+/// A random value for each byte, generated with splitmix64 so that the
+/// chunks of a file never change between versions of this crate.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Split the contents into chunks with FastCDC, a content-defined
+/// chunker: cuts are placed where a rolling hash of the last few dozen
+/// bytes matches a pattern, so an edit only moves the cuts next to it and
+/// the chunks further away come out the same as before.
+pub fn chunks(contents: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = contents;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let (chunk, remaining) = rest.split_at(cut(rest));
+        rest = remaining;
+        Some(chunk)
+    })
+}
+
+/// Splits the contents of a reader into the same chunks as [`chunks`],
+/// holding no more than [`MAX_SIZE`] bytes of them at a time.
+pub struct ChunkReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    consumed: usize,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::with_capacity(MAX_SIZE),
+            consumed: 0,
+        }
+    }
+
+    /// The next chunk, or `None` once the reader is exhausted.
+    pub fn next_chunk(&mut self) -> Result<Option<&[u8]>, std::io::Error> {
+        self.buffer.drain(..self.consumed);
+        (&mut self.reader)
+            .take((MAX_SIZE - self.buffer.len()) as u64)
+            .read_to_end(&mut self.buffer)?;
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        // A cut never looks further ahead than the largest chunk, so the
+        // buffer gives the same cut as the whole of the rest would.
+        self.consumed = cut(&self.buffer);
+        Ok(Some(&self.buffer[..self.consumed]))
+    }
+}
+
+/// The length of the first chunk of the contents.
+fn cut(contents: &[u8]) -> usize {
+    if contents.len() <= MIN_SIZE {
+        return contents.len();
+    }
+    let end = contents.len().min(MAX_SIZE);
+    let normal = end.min(AVERAGE_SIZE);
+    let mut hash: u64 = 0;
+    for (i, byte) in contents.iter().enumerate().take(end).skip(MIN_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// `length` bytes which look random but are the same on every run, from
+/// an xorshift generator.
+#[cfg(test)]
+pub(crate) fn test_bytes(length: usize) -> Vec<u8> {
+    let mut state: u64 = 1;
+    (0..length)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_chunks() {
+    let mut contents = test_bytes(4 * 1024 * 1024);
+    let before: Vec<&[u8]> = chunks(&contents).collect();
+    assert_eq!(before.concat(), contents);
+    assert!(before.iter().all(|chunk| chunk.len() <= MAX_SIZE));
+    assert!(before[..before.len() - 1]
+        .iter()
+        .all(|chunk| chunk.len() >= MIN_SIZE));
+    let before: Vec<Vec<u8>> = before.into_iter().map(Vec::from).collect();
+
+    let mut reader = ChunkReader::new(contents.as_slice());
+    let mut streamed = Vec::new();
+    while let Some(chunk) = reader.next_chunk().unwrap() {
+        streamed.push(chunk.to_vec());
+    }
+    assert_eq!(streamed, before);
+
+    contents.insert(2 * 1024 * 1024, b'!');
+    let after: Vec<&[u8]> = chunks(&contents).collect();
+    assert_eq!(after.concat(), contents);
+    let changed = after
+        .iter()
+        .filter(|chunk| !before.iter().any(|b| b == *chunk))
+        .count();
+    assert!(
+        changed <= 2,
+        "{} of {} chunks changed",
+        changed,
+        after.len()
+    );
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::BTreeMap,
+    env::var,
+    fs::{create_dir_all, exists},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::dot_rev::{read_json, write_json, Error};
+
+/// The name of the current user, used for snapshot signatures.
+pub const USER_NAME: &str = "user.name";
+/// The email address of the current user, used for snapshot signatures.
+pub const USER_EMAIL: &str = "user.email";
+/// The branch a freshly initialized repository starts out on.
+pub const INIT_DEFAULT_BRANCH: &str = "init.default-branch";
+/// Where objects are kept, `packed` by default, with `revtool repack`
+/// moving loose objects into packs, or `loose`.
+pub const STORE_BACKEND: &str = "store.backend";
+/// How loose objects are compressed, `zstd` by default or `none`.
+pub const STORE_COMPRESSION: &str = "store.compression";
+/// Whether objects are checked against their id whenever they are read,
+/// `false` by default.
+pub const STORE_VERIFY: &str = "store.verify";
+/// How many days unreachable objects are kept before `revtool gc` removes
+/// them, 14 by default.
+pub const GC_GRACE_PERIOD: &str = "gc.grace-period";
+/// Keys starting with this prefix define an alias for a command line.
+pub const ALIAS_PREFIX: &str = "alias.";
+
+const KNOWN_KEYS: &[&str] = &[
+    USER_NAME,
+    USER_EMAIL,
+    INIT_DEFAULT_BRANCH,
+    STORE_BACKEND,
+    STORE_COMPRESSION,
+    STORE_VERIFY,
+    GC_GRACE_PERIOD,
+];
+
+/// A single configuration file, a JSON object mapping dotted keys such as
+/// `user.name` to their values.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ConfigFile {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl ConfigFile {
+    /// Read the configuration file at the path, which is empty if the file
+    /// does not exist yet.
+    pub fn load(path: PathBuf) -> Result<Self, Error> {
+        let values = if exists(&path)? {
+            read_json(&path)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(ConfigFile { path, values })
+    }
+
+    /// The configuration file in the given .rev directory.
+    pub fn repository(dot_rev: &Path) -> Result<Self, Error> {
+        ConfigFile::load(dot_rev.join("config"))
+    }
+
+    /// The current user's configuration file, at `REV_USER_CONFIG` if that
+    /// is set and otherwise `rev/config` in the XDG config directory.
+    pub fn user() -> Result<Option<Self>, Error> {
+        let path = if let Ok(path) = var("REV_USER_CONFIG") {
+            PathBuf::from(path)
+        } else if let Ok(config_home) = var("XDG_CONFIG_HOME") {
+            PathBuf::from(config_home).join("rev").join("config")
+        } else if let Ok(home) = var("HOME") {
+            PathBuf::from(home)
+                .join(".config")
+                .join("rev")
+                .join("config")
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(ConfigFile::load(path)?))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn values(&self) -> &BTreeMap<String, String> {
+        &self.values
+    }
+
+    /// Set the key to the value and write the file back out.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        self.values.insert(String::from(key), String::from(value));
+        self.save()
+    }
+
+    /// Remove the key and write the file back out.
+    pub fn unset(&mut self, key: &str) -> Result<(), Error> {
+        self.values.remove(key);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        if let Some(parent) = self.path.parent() {
+            create_dir_all(parent)?;
+        }
+        write_json(&self.values, &self.path)
+    }
+}
+
+/// The layered configuration: environment variables take precedence over
+/// the repository's `.rev/config`, which takes precedence over the user's
+/// configuration file.
+///
+/// The environment variable for a key is `REV_` followed by the key in
+/// upper case with `.` and `-` replaced by `_`, so `user.name` can be set
+/// with `REV_USER_NAME`.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Config {
+    repository: Option<ConfigFile>,
+    user: Option<ConfigFile>,
+}
+
+impl Config {
+    /// Load the configuration, including the repository's layer if a .rev
+    /// directory is given.
+    pub fn load(dot_rev: Option<&Path>) -> Result<Self, Error> {
+        Ok(Config {
+            repository: dot_rev.map(ConfigFile::repository).transpose()?,
+            user: ConfigFile::user()?,
+        })
+    }
+
+    /// The value of the key from the first layer which sets it.
+    pub fn get(&self, key: &str) -> Option<String> {
+        if let Ok(value) = var(env_var(key)) {
+            return Some(value);
+        }
+        self.files()
+            .find_map(|file| file.get(key))
+            .map(String::from)
+    }
+
+    /// The value of the key parsed as a `T`, if it is set.
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Result<Option<T>, Error> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(value) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(Error::BadConfig(String::from(key), value)),
+            },
+        }
+    }
+
+    /// Every key set in a configuration file, or through the environment
+    /// for the keys this crate knows about, with its value.
+    pub fn list(&self) -> BTreeMap<String, String> {
+        let keys = self
+            .files()
+            .flat_map(|file| file.values().keys().map(String::as_str))
+            .chain(KNOWN_KEYS.iter().copied());
+        keys.filter_map(|key| self.get(key).map(|value| (String::from(key), value)))
+            .collect()
+    }
+
+    /// The command line the given alias expands to, if it is one.
+    pub fn alias(&self, name: &str) -> Option<Vec<String>> {
+        self.get(&format!("{}{}", ALIAS_PREFIX, name))
+            .map(|expansion| expansion.split_whitespace().map(String::from).collect())
+    }
+
+    fn files(&self) -> impl Iterator<Item = &ConfigFile> {
+        self.repository.iter().chain(self.user.iter())
+    }
+}
+
+fn env_var(key: &str) -> String {
+    format!("REV_{}", key.to_uppercase().replace(['.', '-'], "_"))
+}
+
+#[test]
+fn test_config_layers() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut repository = ConfigFile::load(tempdir.path().join("repository")).unwrap();
+    let mut user = ConfigFile::load(tempdir.path().join("user/config")).unwrap();
+    repository.set("alias.st", "changes").unwrap();
+    repository.set("test.layers", "repository").unwrap();
+    user.set("test.layers", "user").unwrap();
+    user.set("test.user-only", "user").unwrap();
+    user.set("test.number", "not a number").unwrap();
+    let config = Config {
+        repository: Some(ConfigFile::load(tempdir.path().join("repository")).unwrap()),
+        user: Some(ConfigFile::load(tempdir.path().join("user/config")).unwrap()),
+    };
+    assert_eq!(config.get("test.layers").as_deref(), Some("repository"));
+    assert_eq!(config.get("test.user-only").as_deref(), Some("user"));
+    assert_eq!(config.get("test.missing"), None);
+    assert_eq!(config.alias("st"), Some(vec![String::from("changes")]));
+    assert!(config.get_parsed::<u64>("test.number").is_err());
+    assert_eq!(config.get_parsed::<u64>("test.missing").unwrap(), None);
+    assert_eq!(env_var(INIT_DEFAULT_BRANCH), "REV_INIT_DEFAULT_BRANCH");
+    user.unset("test.user-only").unwrap();
+    assert_eq!(
+        ConfigFile::load(tempdir.path().join("user/config"))
+            .unwrap()
+            .get("test.user-only"),
+        None
+    );
+}
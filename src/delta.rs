@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+/// The length of the blocks of the base which are looked up when
+/// searching for copies. Shorter matches are inserted literally.
+const BLOCK: usize = 16;
+
+const COPY: u8 = 0;
+const INSERT: u8 = 1;
+
+/// Encode `target` as a delta against `base`: the lengths of both followed
+/// by instructions which either copy a range of `base` or insert literal
+/// bytes, all lengths and offsets being LEB128 varints.
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for offset in (0..base.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        blocks
+            .entry(&base[offset..offset + BLOCK])
+            .or_insert(offset);
+    }
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, base.len() as u64);
+    write_varint(&mut delta, target.len() as u64);
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i + BLOCK <= target.len() {
+        let Some(&offset) = blocks.get(&target[i..i + BLOCK]) else {
+            i += 1;
+            continue;
+        };
+        // Grow the match in both directions as far as the bytes agree.
+        let (mut start, mut base_start) = (i, offset);
+        while start > literal_start && base_start > 0 && target[start - 1] == base[base_start - 1] {
+            start -= 1;
+            base_start -= 1;
+        }
+        let mut end = i + BLOCK;
+        let mut base_end = offset + BLOCK;
+        while end < target.len() && base_end < base.len() && target[end] == base[base_end] {
+            end += 1;
+            base_end += 1;
+        }
+        write_insert(&mut delta, &target[literal_start..start]);
+        delta.push(COPY);
+        write_varint(&mut delta, base_start as u64);
+        write_varint(&mut delta, (end - start) as u64);
+        literal_start = end;
+        i = end;
+    }
+    write_insert(&mut delta, &target[literal_start..]);
+    delta
+}
+
+/// Rebuild the target from its base and a delta made by [`encode`], or
+/// `None` if the delta is malformed or was made against a different base.
+pub fn apply(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut delta = delta;
+    if read_varint(&mut delta)? != base.len() as u64 {
+        return None;
+    }
+    let length = read_varint(&mut delta)? as usize;
+    // The length is only trusted once the target has been rebuilt, so a
+    // corrupt one cannot make this allocate more than the inputs' size.
+    let mut target = Vec::with_capacity(length.min(base.len() + delta.len()));
+    while let Some((&op, rest)) = delta.split_first() {
+        delta = rest;
+        match op {
+            COPY => {
+                let offset = read_varint(&mut delta)? as usize;
+                let length = read_varint(&mut delta)? as usize;
+                target.extend_from_slice(base.get(offset..offset.checked_add(length)?)?);
+            }
+            INSERT => {
+                let length = read_varint(&mut delta)? as usize;
+                let (bytes, rest) = delta.split_at_checked(length)?;
+                target.extend_from_slice(bytes);
+                delta = rest;
+            }
+            _ => return None,
+        }
+    }
+    (target.len() == length).then_some(target)
+}
+
+fn write_insert(delta: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        delta.push(INSERT);
+        write_varint(delta, bytes.len() as u64);
+        delta.extend_from_slice(bytes);
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+#[test]
+fn test_delta() {
+    let base: Vec<u8> = (0..2000u32)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+    let mut target = base.clone();
+    target.splice(5000..5010, b"something else entirely".iter().copied());
+    target.extend_from_slice(b"the end\n");
+    let delta = encode(&base, &target);
+    assert!(delta.len() < 100);
+    assert_eq!(apply(&base, &delta), Some(target.clone()));
+    assert_eq!(apply(&target, &delta), None);
+    for (base, target) in [(&b""[..], &b"abc"[..]), (b"abc", b""), (b"short", b"short")] {
+        assert_eq!(apply(base, &encode(base, target)).as_deref(), Some(target));
+    }
+
+    let mut huge = Vec::new();
+    write_varint(&mut huge, base.len() as u64);
+    write_varint(&mut huge, u64::MAX);
+    assert_eq!(apply(&base, &huge), None);
+}
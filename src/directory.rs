@@ -1,18 +1,35 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fmt,
-    fs::{read_dir, File},
-    io::{Read, Write},
-    path::{Path, PathBuf},
+    convert::Infallible,
+    ffi::{OsStr, OsString},
+    fmt::{self, Write as _},
+    fs::{
+        create_dir, exists, read_dir, read_link, remove_dir_all, remove_file, File, FileType,
+        Metadata,
+    },
+    io::{copy, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::{object_id::ObjectId, object_store::ObjectStore};
+use crate::{
+    chunking::ChunkReader,
+    ignore::{Layers, Pattern, Rules},
+    merge::{merge_lines, FileMerge},
+    object_id::ObjectId,
+    object_store::{Ids, ObjectStore},
+};
 
-/// A directory tree, with [`ObjectId`]s at the leaves.
+/// A single level of a directory tree, with [`ObjectId`]s of files and
+/// of the subdirectories' own [`Directory`] objects at the leaves.
+///
+/// Each subdirectory is stored as its own object, so unchanged subtrees
+/// are shared between snapshots and are only loaded from the
+/// [`ObjectStore`] when needed.
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Directory {
+    /// The entries by name, as given by [`encode_name`].
     #[serde(flatten)]
     pub root: BTreeMap<String, DirectoryEntry>,
 }
@@ -20,8 +37,15 @@ pub struct Directory {
 #[derive(Debug)]
 pub enum Error<Store: ObjectStore> {
     ObjectMissing(ObjectId),
+    /// An entry whose name is not a single file name, such as `..`, which
+    /// is never written to the work tree.
+    BadName(String),
+    /// Ignored entries of another kind in the way of writing a
+    /// [`Directory`] out, which are left alone.
+    Conflicts(BTreeMap<PathBuf, Conflict>),
     Store(Store::Error),
     IO(std::io::Error),
+    Serde(serde_json::Error),
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -33,42 +57,285 @@ pub struct Diff {
 
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub enum DiffEntry {
+    /// The new contents of a changed file.
     File(ObjectId),
+    /// The old and new [`Mode`]s of a file whose contents are unchanged.
+    Mode(Mode, Mode),
+    /// The new target of a changed symbolic link.
+    Symlink(#[serde(with = "target")] OsString),
     Directory(Box<Diff>),
 }
 
+/// The result of a three-way merge of [`Directory`] structures.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Merge {
+    /// The merged directory structure. Conflicting paths keep the entry
+    /// from our side.
+    pub directory: Directory,
+    /// The paths which were changed differently on both sides.
+    pub conflicts: BTreeMap<PathBuf, Conflict>,
+}
+
+/// The way in which a path was changed differently on both sides of a
+/// [`Merge`].
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Conflict {
+    /// Overlapping changes to a text file, written out between conflict
+    /// markers.
+    Text,
+    /// Changes to a binary file, which keeps our version.
+    Binary,
+    /// Changes to a file too large, or changed too much, to merge line by
+    /// line, which keeps our version.
+    Large,
+    /// Changes to what kind of entry the path is, or whether it exists,
+    /// which keeps our entry.
+    Entry,
+    /// Changes to a file's [`Mode`], which keeps our mode.
+    Mode,
+    /// Something ignored, and so left alone, in the way of an entry of
+    /// another kind.
+    Ignored,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::Text => write!(f, "text"),
+            Conflict::Binary => write!(f, "binary"),
+            Conflict::Large => write!(f, "large"),
+            Conflict::Entry => write!(f, "entry"),
+            Conflict::Mode => write!(f, "mode"),
+            Conflict::Ignored => write!(f, "ignored"),
+        }
+    }
+}
+
 impl DirectoryEntry {
-    pub fn diff(&self, other: &DirectoryEntry) -> Option<DiffEntry> {
-        use DirectoryEntry::*;
-        match (self, other) {
-            (File(id), File(id_)) => {
-                if id != id_ {
-                    Some(DiffEntry::File(*id_))
-                } else {
-                    None
-                }
+    pub fn diff<Store: ObjectStore>(
+        &self,
+        other: &DirectoryEntry,
+        store: &Store,
+    ) -> Result<Option<DiffEntry>, Error<Store>> {
+        if self == other {
+            return Ok(None);
+        }
+        Ok(Some(match (self, other) {
+            (DirectoryEntry::File(id, mode), DirectoryEntry::File(id_, mode_))
+            | (DirectoryEntry::Chunked(id, mode), DirectoryEntry::Chunked(id_, mode_))
+                if id == id_ =>
+            {
+                DiffEntry::Mode(*mode, *mode_)
+            }
+            (_, DirectoryEntry::File(id, _)) | (_, DirectoryEntry::Chunked(id, _)) => {
+                DiffEntry::File(*id)
+            }
+            (_, DirectoryEntry::Symlink(target)) => DiffEntry::Symlink(target.clone()),
+            (DirectoryEntry::Directory(id), DirectoryEntry::Directory(id_)) => {
+                let d = Directory::read(store, *id)?;
+                let d_ = Directory::read(store, *id_)?;
+                DiffEntry::Directory(Box::new(d.diff(&d_, store)?))
             }
-            (Directory(_), File(id)) => Some(DiffEntry::File(*id)),
-            (File(_), Directory(d)) => Some(DiffEntry::Directory(Box::new(Diff {
+            (_, DirectoryEntry::Directory(id)) => DiffEntry::Directory(Box::new(Diff {
                 deleted: BTreeSet::new(),
-                added: d.root.clone(),
+                added: Directory::read(store, *id)?.root,
                 modified: BTreeMap::new(),
-            }))),
-            (Directory(d), Directory(d_)) => {
-                if d == d_ {
-                    None
-                } else {
-                    Some(DiffEntry::Directory(Box::new(d.diff(d_))))
-                }
+            })),
+        }))
+    }
+
+    /// The entry for a file with the given contents, without storing
+    /// anything.
+    pub fn file(mut contents: &[u8]) -> Self {
+        DirectoryEntry::file_from(&mut contents).expect("reading from memory")
+    }
+
+    /// The entry for a file with the contents read from `reader`, without
+    /// storing anything.
+    pub fn file_from(reader: &mut dyn Read) -> Result<Self, std::io::Error> {
+        match DirectoryEntry::insert_file_from(&mut HashOnly, reader) {
+            Ok(entry) => Ok(entry),
+            Err(Error::IO(err)) => Err(err),
+            Err(err) => Err(std::io::Error::other(format!("{:?}", err))),
+        }
+    }
+
+    /// Insert a file with the given contents into the store, split into
+    /// chunks if it is large, returning its entry.
+    pub fn insert_file<Store: ObjectStore>(
+        store: &mut Store,
+        mut contents: &[u8],
+    ) -> Result<Self, Error<Store>> {
+        DirectoryEntry::insert_file_from(store, &mut contents)
+    }
+
+    /// Insert a file with the contents read from `reader` into the store,
+    /// split into chunks if it is large, returning its entry with the
+    /// default [`Mode`]. Only one chunk of the file is held in memory at a
+    /// time.
+    pub fn insert_file_from<Store: ObjectStore>(
+        store: &mut Store,
+        reader: &mut dyn Read,
+    ) -> Result<Self, Error<Store>> {
+        let mut head = Vec::new();
+        reader
+            .take(CHUNKED_FILE_SIZE as u64)
+            .read_to_end(&mut head)
+            .map_err(Error::IO)?;
+        if head.len() < CHUNKED_FILE_SIZE {
+            return Ok(DirectoryEntry::File(
+                store.insert(&head).map_err(Error::Store)?,
+                Mode::REGULAR,
+            ));
+        }
+        let mut chunk_list = ChunkList { chunks: Vec::new() };
+        let mut chunks = ChunkReader::new(head.as_slice().chain(reader));
+        while let Some(chunk) = chunks.next_chunk().map_err(Error::IO)? {
+            chunk_list
+                .chunks
+                .push(store.insert(chunk).map_err(Error::Store)?);
+        }
+        let v = serde_json::to_vec_pretty(&chunk_list).map_err(Error::Serde)?;
+        Ok(DirectoryEntry::Chunked(
+            store.insert(&v).map_err(Error::Store)?,
+            Mode::REGULAR,
+        ))
+    }
+
+    /// Whether this is the entry of a file rather than of a directory or
+    /// a symbolic link.
+    pub fn is_file(&self) -> bool {
+        matches!(self, DirectoryEntry::File(..) | DirectoryEntry::Chunked(..))
+    }
+
+    /// The [`Mode`] of the file this is the entry of, or `None` for a
+    /// directory.
+    pub fn mode(&self) -> Option<Mode> {
+        match self {
+            DirectoryEntry::File(_, mode) | DirectoryEntry::Chunked(_, mode) => Some(*mode),
+            DirectoryEntry::Directory(_) | DirectoryEntry::Symlink(_) => None,
+        }
+    }
+
+    /// This entry with its [`Mode`] replaced, if it is a file.
+    pub fn with_mode(self, mode: Mode) -> Self {
+        match self {
+            DirectoryEntry::File(id, _) => DirectoryEntry::File(id, mode),
+            DirectoryEntry::Chunked(id, _) => DirectoryEntry::Chunked(id, mode),
+            entry => entry,
+        }
+    }
+
+    /// The contents of the file this is the entry of, put back together
+    /// from its chunks if need be, or `None` for a directory or a symbolic
+    /// link.
+    pub fn read_file<Store: ObjectStore>(
+        &self,
+        store: &Store,
+    ) -> Result<Option<Vec<u8>>, Error<Store>> {
+        if !self.is_file() {
+            return Ok(None);
+        }
+        let mut contents = Vec::new();
+        self.write_file(store, &mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /// Copy the contents of the file this is the entry of to `writer`, one
+    /// object at a time. Directories and symbolic links have no contents.
+    pub fn write_file<Store: ObjectStore>(
+        &self,
+        store: &Store,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error<Store>> {
+        let ids = match self {
+            DirectoryEntry::File(id, _) => vec![*id],
+            DirectoryEntry::Chunked(id, _) => {
+                let chunk_list: ChunkList =
+                    serde_json::from_slice(&read_object(store, *id)?).map_err(Error::Serde)?;
+                chunk_list.chunks
             }
+            DirectoryEntry::Directory(_) | DirectoryEntry::Symlink(_) => Vec::new(),
+        };
+        for id in ids {
+            let mut reader = store
+                .read_stream(id)
+                .map_err(Error::Store)?
+                .ok_or(Error::ObjectMissing(id))?;
+            copy(&mut reader, writer).map_err(Error::IO)?;
         }
+        Ok(())
     }
 }
 
 impl Directory {
-    /// Compute the diff between this directory structure and the one
-    /// which is currently located at the path.
-    pub fn diff(&self, other: &Directory) -> Diff {
+    /// Read the [`Directory`] object with the given [`ObjectId`] out of
+    /// the store.
+    pub fn read<Store: ObjectStore>(store: &Store, id: ObjectId) -> Result<Self, Error<Store>> {
+        match store.read(id).map_err(Error::Store)? {
+            Some(v) => serde_json::from_slice(&v).map_err(Error::Serde),
+            None => Err(Error::ObjectMissing(id)),
+        }
+    }
+
+    /// Insert this [`Directory`] object into the store, returning its
+    /// [`ObjectId`].
+    pub fn insert<Store: ObjectStore>(&self, store: &mut Store) -> Result<ObjectId, Error<Store>> {
+        let v = serde_json::to_vec_pretty(self).map_err(Error::Serde)?;
+        store.insert(&v).map_err(Error::Store)
+    }
+
+    /// Store the directory tree with the given [`ObjectId`] one level per
+    /// object, if it was written with its subdirectories nested inside it
+    /// as trees were before, returning the [`ObjectId`] of its top level.
+    /// Trees which are already stored a level at a time are left alone.
+    pub fn migrate<Store: ObjectStore>(
+        store: &mut Store,
+        id: ObjectId,
+    ) -> Result<ObjectId, Error<Store>> {
+        let object = read_object(store, id)?;
+        if serde_json::from_slice::<Directory>(&object).is_ok() {
+            return Ok(id);
+        }
+        let nested: NestedDirectory = serde_json::from_slice(&object).map_err(Error::Serde)?;
+        nested.insert(store)
+    }
+
+    /// Look up the entry at the given path below this directory, reading
+    /// subdirectories from the store along the way.
+    pub fn entry<Store: ObjectStore>(
+        &self,
+        store: &Store,
+        path: &Path,
+    ) -> Result<Option<DirectoryEntry>, Error<Store>> {
+        let mut names = path
+            .components()
+            .filter(|component| component != &Component::CurDir)
+            .map(|component| encode_name(component.as_os_str()));
+        let Some(name) = names.next() else {
+            return Ok(None);
+        };
+        let mut entry = self.root.get(&name).cloned();
+        for name in names {
+            entry = match entry {
+                Some(DirectoryEntry::Directory(id)) => {
+                    Directory::read(store, id)?.root.remove(&name)
+                }
+                _ => None,
+            };
+        }
+        Ok(entry)
+    }
+
+    /// Compute the diff between this directory structure and another.
+    ///
+    /// Subdirectories with the same [`ObjectId`] on both sides are skipped
+    /// without being read from the store.
+    pub fn diff<Store: ObjectStore>(
+        &self,
+        other: &Directory,
+        store: &Store,
+    ) -> Result<Diff, Error<Store>> {
         let added: BTreeMap<String, DirectoryEntry> = other
             .root
             .iter()
@@ -81,125 +348,829 @@ impl Directory {
             .filter(|(file_name, _dir_entry)| !other.root.contains_key(*file_name))
             .map(|(fname, _dir_entry)| fname.clone())
             .collect();
-        let modified: BTreeMap<String, DiffEntry> = self
-            .root
-            .iter()
-            .filter_map(|(file_name, dir_entry)| {
-                other.root.get(file_name).and_then(|other_dir_entry| {
-                    dir_entry
-                        .diff(other_dir_entry)
-                        .map(|diff| (file_name.clone(), diff))
-                })
-            })
-            .collect();
-        Diff {
+        let mut modified: BTreeMap<String, DiffEntry> = BTreeMap::new();
+        for (file_name, dir_entry) in self.root.iter() {
+            if let Some(other_dir_entry) = other.root.get(file_name) {
+                if let Some(diff) = dir_entry.diff(other_dir_entry, store)? {
+                    modified.insert(file_name.clone(), diff);
+                }
+            }
+        }
+        Ok(Diff {
             added,
             deleted,
             modified,
+        })
+    }
+
+    /// Merge the changes made from `base` to `ours` with the changes made
+    /// from `base` to `theirs`, inserting merged subdirectories into the
+    /// store.
+    ///
+    /// Entries changed on only one side take that side's value, and
+    /// directories present on both sides are merged recursively. Files
+    /// present on both sides are merged line by line with
+    /// [`merge_lines`], keeping conflict markers in the merged file, unless
+    /// one of them is chunked, which is a conflict without being read.
+    /// Anything else changed on both sides is reported as a conflict.
+    pub fn merge<Store: ObjectStore>(
+        store: &mut Store,
+        base: &Directory,
+        ours: &Directory,
+        theirs: &Directory,
+    ) -> Result<Merge, Error<Store>> {
+        let mut conflicts = BTreeMap::new();
+        let directory =
+            Directory::merge_at(store, Path::new(""), base, ours, theirs, &mut conflicts)?;
+        Ok(Merge {
+            directory,
+            conflicts,
+        })
+    }
+
+    fn merge_at<Store: ObjectStore>(
+        store: &mut Store,
+        path: &Path,
+        base: &Directory,
+        ours: &Directory,
+        theirs: &Directory,
+        conflicts: &mut BTreeMap<PathBuf, Conflict>,
+    ) -> Result<Directory, Error<Store>> {
+        let file_names: BTreeSet<&String> = base
+            .root
+            .keys()
+            .chain(ours.root.keys())
+            .chain(theirs.root.keys())
+            .collect();
+        let mut root = BTreeMap::new();
+        for file_name in file_names {
+            let b = base.root.get(file_name);
+            let o = ours.root.get(file_name);
+            let t = theirs.root.get(file_name);
+            let merged = if o == t || b == t {
+                o.cloned()
+            } else if b == o {
+                t.cloned()
+            } else {
+                match (o, t) {
+                    (Some(DirectoryEntry::Directory(o)), Some(DirectoryEntry::Directory(t))) => {
+                        let b = match b {
+                            Some(DirectoryEntry::Directory(b)) => Directory::read(store, *b)?,
+                            _ => Directory::default(),
+                        };
+                        let o = Directory::read(store, *o)?;
+                        let t = Directory::read(store, *t)?;
+                        let merged = Directory::merge_at(
+                            store,
+                            &path.join(display_name(file_name)),
+                            &b,
+                            &o,
+                            &t,
+                            conflicts,
+                        )?;
+                        Some(DirectoryEntry::Directory(merged.insert(store)?))
+                    }
+                    (Some(o), Some(t)) if o.is_file() && t.is_file() => {
+                        // The mode is merged like a whole entry would be.
+                        let (b_mode, o_mode, t_mode) =
+                            (b.and_then(DirectoryEntry::mode), o.mode(), t.mode());
+                        let mode_conflict =
+                            b_mode != o_mode && b_mode != t_mode && o_mode != t_mode;
+                        let mode = if b_mode == o_mode { t_mode } else { o_mode };
+                        let mode = mode.unwrap_or_default();
+                        // Chunked files are not read into memory at all.
+                        let chunked = [b, Some(o), Some(t)]
+                            .into_iter()
+                            .flatten()
+                            .any(|entry| matches!(entry, DirectoryEntry::Chunked(..)));
+                        let merged = if chunked {
+                            FileMerge::TooLarge
+                        } else {
+                            let b = match b {
+                                Some(b) => b.read_file(store)?.unwrap_or_default(),
+                                None => Vec::new(),
+                            };
+                            merge_lines(
+                                &b,
+                                &o.read_file(store)?.unwrap_or_default(),
+                                &t.read_file(store)?.unwrap_or_default(),
+                                "ours",
+                                "theirs",
+                            )
+                        };
+                        // Conflicting contents are the ones to report if
+                        // the mode conflicts as well.
+                        if mode_conflict {
+                            conflicts.insert(path.join(display_name(file_name)), Conflict::Mode);
+                        }
+                        Some(match merged {
+                            FileMerge::Clean(v) => {
+                                DirectoryEntry::insert_file(store, &v)?.with_mode(mode)
+                            }
+                            FileMerge::Conflict(v) => {
+                                conflicts
+                                    .insert(path.join(display_name(file_name)), Conflict::Text);
+                                DirectoryEntry::insert_file(store, &v)?.with_mode(mode)
+                            }
+                            FileMerge::Binary => {
+                                conflicts
+                                    .insert(path.join(display_name(file_name)), Conflict::Binary);
+                                o.clone()
+                            }
+                            FileMerge::TooLarge => {
+                                conflicts
+                                    .insert(path.join(display_name(file_name)), Conflict::Large);
+                                o.clone()
+                            }
+                        })
+                    }
+                    _ => {
+                        conflicts.insert(path.join(display_name(file_name)), Conflict::Entry);
+                        o.cloned()
+                    }
+                }
+            };
+            if let Some(entry) = merged {
+                root.insert(file_name.clone(), entry);
+            }
         }
+        Ok(Directory { root })
     }
 
-    /// Write out the directory structure at the given directory path.
+    /// Write out the directory structure at the given directory path,
+    /// bringing whatever is already there in line with it.
     ///
-    /// The target directory must already exist.
+    /// Files whose contents differ are overwritten, files and directories
+    /// which are not part of this structure are deleted, and missing
+    /// directories are created. Anything matched by the [`Ignores`] is left
+    /// alone.
     pub fn write<Store: ObjectStore>(
         &self,
         store: &Store,
         path: &Path,
+        ignores: &Ignores,
     ) -> Result<(), Error<Store>> {
-        if read_dir(path).is_ok() {
-            for (file_name, entry) in self.root.iter() {
-                match entry {
-                    DirectoryEntry::File(id) => {
-                        let v = store.read(*id).map_err(Error::Store)?;
-                        match v {
-                            Some(v) => {
-                                let mut f = File::options()
-                                    .create(true)
-                                    .write(true)
-                                    .open(path.join(file_name))
-                                    .map_err(Error::IO)?;
-                                f.write(&v).map_err(Error::IO)?;
-                            }
-                            None => return Err(Error::ObjectMissing(*id)),
-                        }
+        let mut conflicts = BTreeMap::new();
+        self.obstructions_at(
+            store,
+            path,
+            Path::new(""),
+            &mut Layers::new(ignores.rules()),
+            &mut conflicts,
+        )?;
+        if !conflicts.is_empty() {
+            return Err(Error::Conflicts(conflicts));
+        }
+        self.write_at(
+            store,
+            path,
+            Path::new(""),
+            &mut Layers::new(ignores.rules()),
+        )
+    }
+
+    /// Find what is ignored, and so would be left alone, but is not the
+    /// kind of entry this directory structure has at the same place below
+    /// `path`, adding each to the `conflicts` before anything is written.
+    /// Links already there are not counted, since writing replaces them
+    /// whether they are ignored or not.
+    fn obstructions_at<Store: ObjectStore>(
+        &self,
+        store: &Store,
+        path: &Path,
+        relative: &Path,
+        layers: &mut Layers,
+        conflicts: &mut BTreeMap<PathBuf, Conflict>,
+    ) -> Result<(), Error<Store>> {
+        if !path.is_dir() {
+            return Ok(());
+        }
+        let rules = Rules::read(path, relative).map_err(Error::IO)?;
+        let pushed = rules.is_some();
+        if let Some(rules) = rules {
+            layers.push(rules);
+        }
+        for (file_name, entry) in &self.root {
+            let name = decode_name(file_name).ok_or_else(|| Error::BadName(file_name.clone()))?;
+            let entry_path = path.join(&name);
+            let file_type = match entry_path.symlink_metadata() {
+                Ok(metadata) => metadata.file_type(),
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(Error::IO(err)),
+            };
+            if file_type.is_symlink() {
+                continue;
+            }
+            let ignored = layers.is_ignored(&relative.join(&name), file_type.is_dir());
+            let obstructed = match entry {
+                DirectoryEntry::Directory(id) if file_type.is_dir() => {
+                    Directory::read(store, *id)?.obstructions_at(
+                        store,
+                        &entry_path,
+                        &relative.join(&name),
+                        layers,
+                        conflicts,
+                    )?;
+                    false
+                }
+                DirectoryEntry::Directory(_) => ignored,
+                DirectoryEntry::File(..) | DirectoryEntry::Chunked(..) => {
+                    ignored && !file_type.is_file()
+                }
+                DirectoryEntry::Symlink(_) => ignored,
+            };
+            if obstructed {
+                conflicts.insert(relative.join(&name), Conflict::Ignored);
+            }
+        }
+        if pushed {
+            layers.pop();
+        }
+        Ok(())
+    }
+
+    /// Write out the directory structure at `path`, which is at `relative`
+    /// relative to the work tree, leaving alone what the [`Layers`] and
+    /// the [`IGNORE_FILE`](crate::ignore::IGNORE_FILE)s already there
+    /// ignore.
+    fn write_at<Store: ObjectStore>(
+        &self,
+        store: &Store,
+        path: &Path,
+        relative: &Path,
+        layers: &mut Layers,
+    ) -> Result<(), Error<Store>> {
+        let names = self
+            .root
+            .keys()
+            .map(|file_name| {
+                decode_name(file_name).ok_or_else(|| Error::BadName(file_name.clone()))
+            })
+            .collect::<Result<Vec<OsString>, _>>()?;
+        if !exists(path).map_err(Error::IO)? {
+            create_dir(path).map_err(Error::IO)?;
+        }
+        let rules = Rules::read(path, relative).map_err(Error::IO)?;
+        let pushed = rules.is_some();
+        if let Some(rules) = rules {
+            layers.push(rules);
+        }
+        for f in read_dir(path).map_err(Error::IO)? {
+            let dir_entry = f.map_err(Error::IO)?;
+            let file_name = encode_name(&dir_entry.file_name());
+            let file_type = dir_entry.file_type().map_err(Error::IO)?;
+            if layers.is_ignored(&relative.join(dir_entry.file_name()), file_type.is_dir()) {
+                continue;
+            }
+            let keep = match self.root.get(&file_name) {
+                Some(DirectoryEntry::Directory(_)) => file_type.is_dir(),
+                Some(DirectoryEntry::Symlink(_)) => file_type.is_symlink(),
+                Some(_) => file_type.is_file(),
+                None => false,
+            };
+            if !keep {
+                remove_any(&dir_entry.path(), file_type).map_err(Error::IO)?;
+            }
+        }
+        for (entry, name) in self.root.values().zip(&names) {
+            let entry_path = path.join(name);
+            // Whatever is left in the way is something ignored. Links are
+            // never written through, so that a link pointing outside of
+            // the work tree cannot get anything written there.
+            let existing = match entry_path.symlink_metadata() {
+                Ok(metadata) => Some(metadata.file_type()),
+                Err(err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => return Err(Error::IO(err)),
+            };
+            if let Some(file_type) = existing {
+                let in_the_way = match entry {
+                    DirectoryEntry::Symlink(target) => {
+                        !file_type.is_symlink()
+                            || read_link(&entry_path).map_err(Error::IO)? != Path::new(target)
+                    }
+                    _ => file_type.is_symlink(),
+                };
+                if in_the_way {
+                    remove_any(&entry_path, file_type).map_err(Error::IO)?;
+                }
+            }
+            match entry {
+                DirectoryEntry::File(_, mode) | DirectoryEntry::Chunked(_, mode) => {
+                    let mut unchanged = false;
+                    if exists(&entry_path).map_err(Error::IO)? {
+                        let mut f = File::open(&entry_path).map_err(Error::IO)?;
+                        let existing = DirectoryEntry::file_from(&mut f).map_err(Error::IO)?;
+                        unchanged = existing.with_mode(*mode) == *entry;
                     }
-                    DirectoryEntry::Directory(dir) => {
-                        dir.write(store, PathBuf::from(path).join(file_name).as_path())?;
+                    if !unchanged {
+                        let mut f = File::options()
+                            .create(true)
+                            .write(true)
+                            .truncate(true)
+                            .open(&entry_path)
+                            .map_err(Error::IO)?;
+                        entry.write_file(store, &mut f)?;
+                    }
+                    mode.apply(&entry_path).map_err(Error::IO)?;
+                }
+                DirectoryEntry::Directory(id) => {
+                    Directory::read(store, *id)?.write_at(
+                        store,
+                        entry_path.as_path(),
+                        &relative.join(name),
+                        layers,
+                    )?;
+                }
+                DirectoryEntry::Symlink(target) => {
+                    if !entry_path.is_symlink() {
+                        symlink(target, &entry_path).map_err(Error::IO)?;
                     }
                 }
             }
         }
+        if pushed {
+            layers.pop();
+        }
         Ok(())
     }
 }
 
-/// The set of file names which we will ignore at any level.
+/// Remove whatever is at the path without following it if it is a link.
+fn remove_any(path: &Path, file_type: FileType) -> std::io::Result<()> {
+    if file_type.is_dir() {
+        remove_dir_all(path)
+    } else {
+        remove_file(path)
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &OsStr, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &OsStr, path: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        format!("cannot create the symbolic link {}", path.display()),
+    ))
+}
+
+/// The name under which a file is kept in a [`Directory`].
+///
+/// Names which are valid UTF-8 are kept as they are. Any other byte is
+/// written as a `/` followed by two hexadecimal digits, which cannot be
+/// mistaken for part of a real name since no name contains a `/`.
+pub fn encode_name(name: &OsStr) -> String {
+    let mut encoded = String::new();
+    for chunk in name.as_encoded_bytes().utf8_chunks() {
+        encoded.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            write!(encoded, "/{:02x}", byte).expect("writing to a String");
+        }
+    }
+    encoded
+}
+
+/// The file name kept in a [`Directory`] under the given name, undoing
+/// [`encode_name`].
+///
+/// `None` unless the name is exactly what [`encode_name`] gives for a
+/// single file name, so that `..`, `.`, names with a separator in them and
+/// other spellings of the same bytes are never written to the work tree.
+pub fn decode_name(name: &str) -> Option<OsString> {
+    let mut bytes = Vec::new();
+    let mut rest = name.as_bytes();
+    while let Some((&first, after)) = rest.split_first() {
+        if first != b'/' {
+            bytes.push(first);
+            rest = after;
+            continue;
+        }
+        let hex = after.get(..2)?;
+        if !hex
+            .iter()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(c))
+        {
+            return None;
+        }
+        bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+        rest = &after[2..];
+    }
+    let decoded = os_string_from_bytes(bytes);
+    let mut components = Path::new(&decoded).components();
+    let single = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(component)), None) if component == decoded
+    );
+    (single && !decoded.as_encoded_bytes().contains(&0) && encode_name(&decoded) == name)
+        .then_some(decoded)
+}
+
+/// The name to show for one kept in a [`Directory`], which is the name
+/// itself where it cannot be decoded.
+fn display_name(name: &str) -> OsString {
+    decode_name(name).unwrap_or_else(|| OsString::from(name))
+}
+
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn read_object<Store: ObjectStore>(store: &Store, id: ObjectId) -> Result<Vec<u8>, Error<Store>> {
+    store
+        .read(id)
+        .map_err(Error::Store)?
+        .ok_or(Error::ObjectMissing(id))
+}
+
+/// The gitignore-style [`Pattern`]s for paths which are left out of
+/// snapshots and left alone when writing them out.
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct Ignores {
-    pub set: BTreeSet<String>,
+    /// Where several patterns match a path, the last one decides.
+    #[serde(alias = "set")]
+    pub patterns: Vec<Pattern>,
+}
+
+impl Ignores {
+    /// The [`Rules`] applying to the whole work tree, underneath those of
+    /// any [`IGNORE_FILE`](crate::ignore::IGNORE_FILE)s.
+    pub fn rules(&self) -> Rules {
+        Rules {
+            source: PathBuf::from(".rev/ignores"),
+            directory: PathBuf::new(),
+            patterns: (1..).zip(self.patterns.iter().cloned()).collect(),
+        }
+    }
 }
 
 impl Default for Ignores {
     fn default() -> Self {
         Ignores {
-            set: vec![String::from(".rev")].into_iter().collect(),
+            patterns: vec![Pattern::new(".rev")],
         }
     }
 }
 
+/// Files at least this large are split into chunks with
+/// [`chunks`](crate::chunking::chunks), so that versions of the file with
+/// small edits share most of their chunks.
+pub const CHUNKED_FILE_SIZE: usize = 1024 * 1024;
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "RawEntry", into = "RawEntry")]
 pub enum DirectoryEntry {
-    Directory(Box<Directory>),
-    File(ObjectId),
+    /// The [`ObjectId`] of a subdirectory's [`Directory`] object.
+    Directory(ObjectId),
+    /// The [`ObjectId`] of a file's contents, and its [`Mode`].
+    File(ObjectId, Mode),
+    /// The [`ObjectId`] of a large file's [`ChunkList`], and its [`Mode`].
+    Chunked(ObjectId, Mode),
+    /// The target of a symbolic link, exactly as the link holds it.
+    Symlink(OsString),
+}
+
+/// How a [`DirectoryEntry`] is stored, with files of the default [`Mode`]
+/// stored as they were before modes were recorded, so that trees written
+/// then still read and hash the same.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "DirectoryEntry")]
+enum RawEntry {
+    Directory(ObjectId),
+    File(RawFile),
+    Chunked(RawFile),
+    Symlink(#[serde(with = "target")] OsString),
+}
+
+/// Symbolic link targets are stored as strings where they are valid UTF-8,
+/// and as arrays of their bytes otherwise.
+mod target {
+    use std::ffi::OsString;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum RawTarget {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(target: &OsString, serializer: S) -> Result<S::Ok, S::Error> {
+        match target.to_str() {
+            Some(text) => RawTarget::Text(String::from(text)),
+            None => RawTarget::Bytes(target.as_encoded_bytes().to_vec()),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OsString, D::Error> {
+        Ok(match RawTarget::deserialize(deserializer)? {
+            RawTarget::Text(text) => OsString::from(text),
+            RawTarget::Bytes(bytes) => super::os_string_from_bytes(bytes),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawFile {
+    Regular(ObjectId),
+    WithMode(ObjectId, Mode),
+}
+
+impl From<RawFile> for (ObjectId, Mode) {
+    fn from(raw: RawFile) -> Self {
+        match raw {
+            RawFile::Regular(id) => (id, Mode::REGULAR),
+            RawFile::WithMode(id, mode) => (id, mode),
+        }
+    }
+}
+
+impl From<(ObjectId, Mode)> for RawFile {
+    fn from((id, mode): (ObjectId, Mode)) -> Self {
+        if mode == Mode::REGULAR {
+            RawFile::Regular(id)
+        } else {
+            RawFile::WithMode(id, mode)
+        }
+    }
+}
+
+impl From<RawEntry> for DirectoryEntry {
+    fn from(raw: RawEntry) -> Self {
+        match raw {
+            RawEntry::Directory(id) => DirectoryEntry::Directory(id),
+            RawEntry::File(file) => {
+                let (id, mode) = file.into();
+                DirectoryEntry::File(id, mode)
+            }
+            RawEntry::Chunked(file) => {
+                let (id, mode) = file.into();
+                DirectoryEntry::Chunked(id, mode)
+            }
+            RawEntry::Symlink(target) => DirectoryEntry::Symlink(target),
+        }
+    }
+}
+
+impl From<DirectoryEntry> for RawEntry {
+    fn from(entry: DirectoryEntry) -> Self {
+        match entry {
+            DirectoryEntry::Directory(id) => RawEntry::Directory(id),
+            DirectoryEntry::File(id, mode) => RawEntry::File((id, mode).into()),
+            DirectoryEntry::Chunked(id, mode) => RawEntry::Chunked((id, mode).into()),
+            DirectoryEntry::Symlink(target) => RawEntry::Symlink(target),
+        }
+    }
+}
+
+/// A directory tree as it was stored before each subdirectory became its
+/// own object, with whole subtrees nested in their parents.
+#[derive(Deserialize)]
+struct NestedDirectory {
+    #[serde(flatten)]
+    root: BTreeMap<String, NestedEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NestedEntry {
+    Entry(DirectoryEntry),
+    Directory {
+        #[serde(rename = "Directory")]
+        directory: NestedDirectory,
+    },
+}
+
+impl NestedDirectory {
+    /// Insert each level of this tree into the store, the deepest first,
+    /// returning the [`ObjectId`] of the top level.
+    fn insert<Store: ObjectStore>(self, store: &mut Store) -> Result<ObjectId, Error<Store>> {
+        let mut root = BTreeMap::new();
+        for (name, entry) in self.root {
+            let entry = match entry {
+                NestedEntry::Entry(entry) => entry,
+                NestedEntry::Directory { directory } => {
+                    DirectoryEntry::Directory(directory.insert(store)?)
+                }
+            };
+            root.insert(name, entry);
+        }
+        Directory { root }.insert(store)
+    }
+}
+
+/// The Unix permission bits of a file, written in octal.
+///
+/// Only whether a file is executable is taken from the work tree, as
+/// [`Mode::REGULAR`] or [`Mode::EXECUTABLE`], so that differences in umask
+/// do not show up as changes, but any mode recorded is restored.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Mode(u32);
+
+impl Mode {
+    pub const REGULAR: Mode = Mode(0o644);
+    pub const EXECUTABLE: Mode = Mode(0o755);
+
+    pub fn new(bits: u32) -> Self {
+        Mode(bits & 0o7777)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_executable(self) -> bool {
+        self.0 & 0o111 != 0
+    }
+
+    /// The mode recorded for a file with the given metadata.
+    pub fn of(metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if Mode::new(metadata.permissions().mode()).is_executable() {
+                return Mode::EXECUTABLE;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = metadata;
+        Mode::REGULAR
+    }
+
+    /// Give the file at `path` this mode, unless it already has the one
+    /// which would be recorded for it.
+    pub fn apply(self, path: &Path) -> std::io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::{
+                fs::{set_permissions, Permissions},
+                os::unix::fs::PermissionsExt,
+            };
+            if Mode::of(&path.metadata()?) != self {
+                set_permissions(path, Permissions::from_mode(self.0))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::REGULAR
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:o}", self.0)
+    }
+}
+
+impl TryFrom<String> for Mode {
+    type Error = std::num::ParseIntError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        u32::from_str_radix(&s, 8).map(Mode::new)
+    }
+}
+
+impl From<Mode> for String {
+    fn from(mode: Mode) -> Self {
+        mode.to_string()
+    }
+}
+
+/// An [`ObjectStore`] which only hashes what is inserted, for finding the
+/// entry a file would have.
+#[derive(Debug)]
+struct HashOnly;
+
+impl ObjectStore for HashOnly {
+    type Error = Infallible;
+
+    fn has(&self, _id: ObjectId) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    fn read(&self, _id: ObjectId) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(None)
+    }
+
+    fn insert(&mut self, object: &[u8]) -> Result<ObjectId, Self::Error> {
+        Ok(object.into())
+    }
+
+    fn ids(&self) -> Ids<'_, Self::Error> {
+        Box::new(std::iter::empty())
+    }
+
+    fn remove(&mut self, _id: ObjectId) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The [`ObjectId`]s of the chunks a large file was split into, which put
+/// together in order are the file's contents.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkList {
+    pub chunks: Vec<ObjectId>,
 }
 
 impl Directory {
+    /// Build the [`Directory`] for the given path, inserting every file
+    /// and every subdirectory's [`Directory`] object into the store.
+    /// Paths which `ignores`, or the
+    /// [`IGNORE_FILE`](crate::ignore::IGNORE_FILE) in any directory, ignore
+    /// are left out.
+    ///
+    /// The returned top level is not inserted itself.
     pub fn new<Store: ObjectStore>(
         dir: &Path,
         ignores: &Ignores,
         store: &mut Store,
     ) -> Result<Self, Error<Store>> {
+        Directory::new_at(dir, Path::new(""), &mut Layers::new(ignores.rules()), store)
+    }
+
+    /// Build the [`Directory`] for `dir`, which is at `path` relative to
+    /// the work tree, leaving out what the [`Layers`] and the
+    /// [`IGNORE_FILE`](crate::ignore::IGNORE_FILE)s within it ignore.
+    fn new_at<Store: ObjectStore>(
+        dir: &Path,
+        path: &Path,
+        layers: &mut Layers,
+        store: &mut Store,
+    ) -> Result<Self, Error<Store>> {
+        let rules = Rules::read(dir, path).map_err(Error::IO)?;
+        let pushed = rules.is_some();
+        if let Some(rules) = rules {
+            layers.push(rules);
+        }
         let mut root = BTreeMap::new();
         for f in std::fs::read_dir(dir).map_err(Error::IO)? {
             let dir_entry = f.map_err(Error::IO)?;
-            if ignores
-                .set
-                .contains(&dir_entry.file_name().into_string().unwrap())
-            {
+            let file_type = dir_entry.file_type().map_err(Error::IO)?;
+            let entry_path = path.join(dir_entry.file_name());
+            if layers.is_ignored(&entry_path, file_type.is_dir()) {
                 continue;
             }
-            let file_type = dir_entry.file_type().map_err(Error::IO)?;
             if file_type.is_dir() {
-                let directory = Directory::new(dir_entry.path().as_path(), ignores, store)?;
+                let directory =
+                    Directory::new_at(dir_entry.path().as_path(), &entry_path, layers, store)?;
                 root.insert(
-                    dir_entry.file_name().into_string().unwrap(),
-                    DirectoryEntry::Directory(Box::new(directory)),
+                    encode_name(&dir_entry.file_name()),
+                    DirectoryEntry::Directory(directory.insert(store)?),
+                );
+            } else if file_type.is_symlink() {
+                let target = read_link(dir_entry.path()).map_err(Error::IO)?;
+                root.insert(
+                    encode_name(&dir_entry.file_name()),
+                    DirectoryEntry::Symlink(target.into_os_string()),
                 );
             } else if file_type.is_file() {
-                let id = ObjectId::try_from(dir_entry.path().as_path()).map_err(Error::IO)?;
+                let mut f = File::open(dir_entry.path()).map_err(Error::IO)?;
+                let mode = Mode::of(&f.metadata().map_err(Error::IO)?);
                 root.insert(
-                    dir_entry.file_name().into_string().unwrap(),
-                    DirectoryEntry::File(id),
+                    encode_name(&dir_entry.file_name()),
+                    DirectoryEntry::insert_file_from(store, &mut f)?.with_mode(mode),
                 );
-                let mut v = Vec::new();
-                let mut obj_file = File::options()
-                    .read(true)
-                    .open(dir_entry.path())
-                    .map_err(Error::IO)?;
-                obj_file.read_to_end(&mut v).map_err(Error::IO)?;
-                store.insert(&v).map_err(Error::Store)?;
             } else {
-                eprintln!(
-                    "TODO support things which aren't files or directories: {:?}",
-                    dir_entry.file_name()
+                // Such as FIFOs, sockets and devices, which have no contents
+                // to store.
+                log::warn!(
+                    "skipping {:?}, which is not a file, directory or symbolic link",
+                    dir_entry.path()
                 );
             }
         }
+        if pushed {
+            layers.pop();
+        }
         Ok(Directory { root })
     }
 }
 
+impl Diff {
+    /// Whether the two directory structures were identical.
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty() && self.added.is_empty() && self.modified.is_empty()
+    }
+}
+
 impl fmt::Display for Diff {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         enum DiffStackItem {
@@ -210,19 +1181,24 @@ impl fmt::Display for Diff {
         let mut stack: Vec<DiffStackItem> = vec![];
 
         for (path, dir_entry) in self.added.clone() {
-            stack.push(DiffStackItem::Added(PathBuf::from(path), dir_entry));
+            stack.push(DiffStackItem::Added(display_name(&path).into(), dir_entry));
         }
         for (path, diff_entry) in self.modified.clone() {
-            stack.push(DiffStackItem::Modified(PathBuf::from(path), diff_entry));
+            stack.push(DiffStackItem::Modified(
+                display_name(&path).into(),
+                diff_entry,
+            ));
         }
         for path in self.deleted.clone() {
-            stack.push(DiffStackItem::Deleted(PathBuf::from(path)));
+            stack.push(DiffStackItem::Deleted(display_name(&path).into()));
         }
 
         enum DiffItem {
             Deleted,
             Added,
             Modified,
+            ModeChanged(Mode, Mode),
+            Retargeted(OsString),
         }
         let mut diff_paths: BTreeMap<PathBuf, DiffItem> = BTreeMap::new();
 
@@ -231,33 +1207,34 @@ impl fmt::Display for Diff {
                 DiffStackItem::Deleted(path) => {
                     diff_paths.insert(path, DiffItem::Deleted);
                 }
-                DiffStackItem::Added(path, dir_entry) => match dir_entry {
-                    DirectoryEntry::File(_) => {
-                        diff_paths.insert(path, DiffItem::Added);
-                    }
-                    DirectoryEntry::Directory(dir) => {
-                        if dir.root.is_empty() {
-                            diff_paths.insert(path, DiffItem::Added);
-                        } else {
-                            for (dir_name, dir_entry) in dir.root.clone() {
-                                stack.push(DiffStackItem::Added(path.join(dir_name), dir_entry));
-                            }
-                        }
-                    }
-                },
+                DiffStackItem::Added(path, _dir_entry) => {
+                    diff_paths.insert(path, DiffItem::Added);
+                }
                 DiffStackItem::Modified(path, diff_entry) => match diff_entry {
                     DiffEntry::File(_) => {
                         diff_paths.insert(path, DiffItem::Modified);
                     }
+                    DiffEntry::Mode(old, new) => {
+                        diff_paths.insert(path, DiffItem::ModeChanged(old, new));
+                    }
+                    DiffEntry::Symlink(target) => {
+                        diff_paths.insert(path, DiffItem::Retargeted(target));
+                    }
                     DiffEntry::Directory(diff) => {
                         for (dir_name, dir_entry) in diff.added.clone() {
-                            stack.push(DiffStackItem::Added(path.join(dir_name), dir_entry))
+                            stack.push(DiffStackItem::Added(
+                                path.join(display_name(&dir_name)),
+                                dir_entry,
+                            ))
                         }
                         for (dir_name, diff_entry) in diff.modified.clone() {
-                            stack.push(DiffStackItem::Modified(path.join(dir_name), diff_entry))
+                            stack.push(DiffStackItem::Modified(
+                                path.join(display_name(&dir_name)),
+                                diff_entry,
+                            ))
                         }
                         for dir_name in diff.deleted.clone() {
-                            stack.push(DiffStackItem::Deleted(path.join(dir_name)))
+                            stack.push(DiffStackItem::Deleted(path.join(display_name(&dir_name))))
                         }
                     }
                 },
@@ -266,9 +1243,18 @@ impl fmt::Display for Diff {
 
         for (path, diff_item) in diff_paths {
             match diff_item {
-                DiffItem::Deleted => writeln!(f, "D {}", path.to_str().unwrap())?,
-                DiffItem::Added => writeln!(f, "A {}", path.to_str().unwrap())?,
-                DiffItem::Modified => writeln!(f, "M {}", path.to_str().unwrap())?,
+                DiffItem::Deleted => writeln!(f, "D {}", path.display())?,
+                DiffItem::Added => writeln!(f, "A {}", path.display())?,
+                DiffItem::Modified => writeln!(f, "M {}", path.display())?,
+                DiffItem::ModeChanged(old, new) => {
+                    writeln!(f, "M {} (mode {} -> {})", path.display(), old, new)?
+                }
+                DiffItem::Retargeted(target) => writeln!(
+                    f,
+                    "M {} -> {}",
+                    path.display(),
+                    Path::new(&target).display()
+                )?,
             }
         }
         Ok(())
@@ -287,7 +1273,7 @@ fn test_diff_display() {
     let deleted_foo = BTreeSet::from([String::from("foo")]);
     let added_bar: BTreeMap<String, DirectoryEntry> = vec![(
         String::from("bar"),
-        DirectoryEntry::File(ObjectId::from(&vec![])),
+        DirectoryEntry::File(ObjectId::from(&vec![]), Mode::REGULAR),
     )]
     .into_iter()
     .collect();
@@ -362,17 +1348,472 @@ fn test_directory() {
     let codebase = Directory::new(
         dir.as_path(),
         &Ignores {
-            set: vec![
-                String::from(".git"),
-                String::from(".rev"),
-                String::from("target"),
-            ]
-            .into_iter()
-            .collect(),
+            patterns: vec![
+                Pattern::new(".git"),
+                Pattern::new(".rev"),
+                Pattern::new("/target/"),
+            ],
         },
         &mut store,
     )
     .unwrap();
     let readme_path = String::from("README.md");
-    assert!(codebase.root.get(&readme_path).is_some());
+    assert!(codebase.root.contains_key(&readme_path));
+}
+
+#[test]
+fn test_write() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    use std::fs::{create_dir_all, read_to_string, write};
+    let source = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    create_dir_all(source.path().join("a/b")).unwrap();
+    write(source.path().join("a/b/c"), "c").unwrap();
+    write(source.path().join("d"), "d").unwrap();
+    create_dir_all(target.path().join("e")).unwrap();
+    create_dir_all(target.path().join(".rev")).unwrap();
+    write(target.path().join("a"), "a").unwrap();
+    write(target.path().join("d"), "not d").unwrap();
+    write(target.path().join("e/f"), "f").unwrap();
+    write(target.path().join(".rev/g"), "g").unwrap();
+    let ignores = Ignores::default();
+    let mut store = InMemoryObjectStore::new();
+    let directory = Directory::new(source.path(), &ignores, &mut store).unwrap();
+    directory.write(&store, target.path(), &ignores).unwrap();
+    assert_eq!(read_to_string(target.path().join("a/b/c")).unwrap(), "c");
+    assert_eq!(read_to_string(target.path().join("d")).unwrap(), "d");
+    assert!(!target.path().join("e").exists());
+    assert!(target.path().join(".rev/g").exists());
+    assert_eq!(
+        Directory::new(target.path(), &ignores, &mut store).unwrap(),
+        directory
+    );
+
+    // Ignored entries of the wrong kind are reported before anything is
+    // written, rather than half way through.
+    let source = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    write(source.path().join("cache"), "cache").unwrap();
+    create_dir_all(source.path().join("out/x")).unwrap();
+    write(source.path().join("new"), "new").unwrap();
+    create_dir_all(target.path().join("cache")).unwrap();
+    write(target.path().join("out"), "out").unwrap();
+    let directory = Directory::new(source.path(), &ignores, &mut store).unwrap();
+    let mut ignoring = Ignores::default();
+    ignoring
+        .patterns
+        .extend([Pattern::new("cache"), Pattern::new("out")]);
+    assert!(matches!(
+        directory.write(&store, target.path(), &ignoring),
+        Err(Error::Conflicts(conflicts)) if conflicts == BTreeMap::from([
+            (PathBuf::from("cache"), Conflict::Ignored),
+            (PathBuf::from("out"), Conflict::Ignored),
+        ])
+    ));
+    assert!(!target.path().join("new").exists());
+    assert!(target.path().join("cache").is_dir());
+}
+
+#[test]
+fn test_ignores() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    use std::fs::{create_dir_all, write};
+    let tempdir = tempfile::tempdir().unwrap();
+    for dir in ["build", "src/build", "logs/a/b"] {
+        create_dir_all(tempdir.path().join(dir)).unwrap();
+    }
+    for file in [
+        "main.o",
+        "keep.o",
+        "build/out",
+        "src/build/lib.rs",
+        "src/lib.o",
+        "logs/a/b/c.tmp",
+        "logs/a/d.log",
+        "logs/a/e.log",
+    ] {
+        write(tempdir.path().join(file), file).unwrap();
+    }
+    write(tempdir.path().join("logs/.revignore"), "*.log\n!e.log\n").unwrap();
+    let ignores: Ignores =
+        serde_json::from_str(r#"{"set": ["*.o", "!keep.o", "/build", "logs/**/*.tmp"]}"#).unwrap();
+    let mut layers = Layers::new(ignores.rules());
+    assert!(layers.is_ignored(Path::new("src/lib.o"), false));
+    assert!(layers.is_ignored(Path::new("build/out"), false));
+    assert!(!layers.is_ignored(Path::new("keep.o"), false));
+    layers
+        .read_down_to(tempdir.path(), Path::new("logs/a"))
+        .unwrap();
+    let found = layers.explain(Path::new("logs/a/d.log"), false).unwrap();
+    assert_eq!(found.to_string(), "logs/.revignore:1:*.log");
+    assert!(!layers.is_ignored(Path::new("logs/a/e.log"), false));
+    assert!(!layers.is_ignored(Path::new("d.log"), false));
+    let mut store = InMemoryObjectStore::new();
+    let directory = Directory::new(tempdir.path(), &ignores, &mut store).unwrap();
+    let mut files = Vec::new();
+    let mut stack = vec![(PathBuf::new(), directory)];
+    while let Some((path, directory)) = stack.pop() {
+        for (name, entry) in directory.root {
+            match entry {
+                DirectoryEntry::Directory(id) => {
+                    stack.push((path.join(name), Directory::read(&store, id).unwrap()))
+                }
+                _ => files.push(path.join(name)),
+            }
+        }
+    }
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            "keep.o",
+            "logs/.revignore",
+            "logs/a/e.log",
+            "src/build/lib.rs"
+        ]
+        .map(PathBuf::from)
+        .to_vec()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_modes() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    use std::{
+        fs::{set_permissions, write, Permissions},
+        os::unix::fs::PermissionsExt,
+    };
+    let id = ObjectId::from(b"#!/bin/sh\n".as_slice());
+    let old = format!(r#"{{"run": {{"File": "{}"}}}}"#, id);
+    let directory: Directory = serde_json::from_str(&old).unwrap();
+    assert_eq!(
+        directory.root["run"],
+        DirectoryEntry::File(id, Mode::REGULAR)
+    );
+    assert_eq!(
+        serde_json::to_value(&directory).unwrap(),
+        serde_json::from_str::<serde_json::Value>(&old).unwrap()
+    );
+    let executable = DirectoryEntry::File(id, Mode::EXECUTABLE);
+    let json = serde_json::to_string(&executable).unwrap();
+    assert_eq!(json, format!(r#"{{"File":["{}","755"]}}"#, id));
+    assert_eq!(
+        serde_json::from_str::<DirectoryEntry>(&json).unwrap(),
+        executable
+    );
+
+    let source = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    write(source.path().join("run"), "#!/bin/sh\n").unwrap();
+    set_permissions(source.path().join("run"), Permissions::from_mode(0o775)).unwrap();
+    write(target.path().join("run"), "#!/bin/sh\n").unwrap();
+    let mut store = InMemoryObjectStore::new();
+    let ignores = Ignores::default();
+    let before = Directory::new(target.path(), &ignores, &mut store).unwrap();
+    let after = Directory::new(source.path(), &ignores, &mut store).unwrap();
+    assert_eq!(after.root["run"], executable);
+    let diff = before.diff(&after, &store).unwrap();
+    assert_eq!(
+        diff.modified["run"],
+        DiffEntry::Mode(Mode::REGULAR, Mode::EXECUTABLE)
+    );
+    assert_eq!(diff.to_string(), "M run (mode 644 -> 755)\n");
+    after.write(&store, target.path(), &ignores).unwrap();
+    let metadata = target.path().join("run").metadata().unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+    before.write(&store, target.path(), &ignores).unwrap();
+    let metadata = target.path().join("run").metadata().unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    use std::{
+        fs::{create_dir_all, read_dir, read_to_string, remove_file, write},
+        os::unix::{ffi::OsStrExt, fs::symlink},
+    };
+    let outside = tempfile::tempdir().unwrap();
+    let victim = outside.path().join("victim");
+    write(&victim, "victim").unwrap();
+    let source = tempfile::tempdir().unwrap();
+    write(source.path().join("f"), "f").unwrap();
+    create_dir_all(source.path().join("cache")).unwrap();
+    write(source.path().join("cache/c"), "c").unwrap();
+    symlink("f", source.path().join("inside")).unwrap();
+    symlink(outside.path(), source.path().join("outside")).unwrap();
+    let mut store = InMemoryObjectStore::new();
+    let ignores = Ignores::default();
+    let directory = Directory::new(source.path(), &ignores, &mut store).unwrap();
+    assert_eq!(
+        directory.root["inside"],
+        DirectoryEntry::Symlink(OsString::from("f"))
+    );
+    assert_eq!(
+        directory.root["outside"],
+        DirectoryEntry::Symlink(outside.path().into())
+    );
+
+    // Links in the way are replaced rather than written through, even
+    // ignored ones.
+    let target = tempfile::tempdir().unwrap();
+    symlink(&victim, target.path().join("f")).unwrap();
+    symlink(outside.path(), target.path().join("cache")).unwrap();
+    let ignores = Ignores {
+        patterns: vec![Pattern::new(".rev"), Pattern::new("cache")],
+    };
+    directory.write(&store, target.path(), &ignores).unwrap();
+    assert_eq!(read_to_string(&victim).unwrap(), "victim");
+    assert_eq!(read_dir(outside.path()).unwrap().count(), 1);
+    assert_eq!(read_to_string(target.path().join("f")).unwrap(), "f");
+    assert_eq!(read_to_string(target.path().join("inside")).unwrap(), "f");
+    assert_eq!(read_to_string(target.path().join("cache/c")).unwrap(), "c");
+    assert_eq!(
+        read_link(target.path().join("outside")).unwrap(),
+        outside.path()
+    );
+
+    remove_file(source.path().join("inside")).unwrap();
+    symlink("cache/c", source.path().join("inside")).unwrap();
+    let retargeted = Directory::new(source.path(), &Ignores::default(), &mut store).unwrap();
+    let diff = directory.diff(&retargeted, &store).unwrap();
+    assert_eq!(diff.to_string(), "M inside -> cache/c\n");
+    retargeted.write(&store, target.path(), &ignores).unwrap();
+    assert_eq!(read_to_string(target.path().join("inside")).unwrap(), "c");
+
+    let bytes = OsStr::from_bytes(b"caf\xe9");
+    symlink(bytes, source.path().join("bytes")).unwrap();
+    let with_bytes = Directory::new(source.path(), &Ignores::default(), &mut store).unwrap();
+    let id = with_bytes.insert(&mut store).unwrap();
+    assert_eq!(Directory::read(&store, id).unwrap(), with_bytes);
+    with_bytes.write(&store, target.path(), &ignores).unwrap();
+    assert_eq!(read_link(target.path().join("bytes")).unwrap(), bytes);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_names() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    use std::{
+        fs::{create_dir, read, write},
+        os::unix::ffi::OsStrExt,
+    };
+    let name = OsStr::from_bytes(b"caf\xe9");
+    assert_eq!(encode_name(name), "caf/e9");
+    assert_eq!(decode_name("caf/e9").unwrap(), name);
+    assert_eq!(decode_name("caf\u{e9}").unwrap(), "caf\u{e9}");
+    for bad in [
+        "..",
+        ".",
+        "",
+        "/2e/2e",
+        "a/2fb",
+        "a/b",
+        "caf/E9",
+        "caf/c3/a9",
+        "a/0",
+    ] {
+        assert_eq!(decode_name(bad), None, "{}", bad);
+    }
+    let source = tempfile::tempdir().unwrap();
+    create_dir(source.path().join(name)).unwrap();
+    write(source.path().join(name).join(name), "contents").unwrap();
+    let mut store = InMemoryObjectStore::new();
+    let ignores = Ignores::default();
+    let directory = Directory::new(source.path(), &ignores, &mut store).unwrap();
+    assert_eq!(
+        directory
+            .entry(&store, &Path::new(name).join(name))
+            .unwrap(),
+        Some(DirectoryEntry::file(b"contents"))
+    );
+    let diff = Directory::default().diff(&directory, &store).unwrap();
+    assert_eq!(diff.to_string(), "A caf\u{fffd}\n");
+
+    let target = tempfile::tempdir().unwrap();
+    directory.write(&store, target.path(), &ignores).unwrap();
+    assert_eq!(
+        read(target.path().join(name).join(name)).unwrap(),
+        b"contents"
+    );
+    directory.write(&store, target.path(), &ignores).unwrap();
+    assert_eq!(
+        Directory::new(target.path(), &ignores, &mut store).unwrap(),
+        directory
+    );
+    let escaping = Directory {
+        root: BTreeMap::from([(String::from("/2e/2e"), DirectoryEntry::file(b"escaped"))]),
+    };
+    let inner = target.path().join("inner");
+    assert!(matches!(
+        escaping.write(&store, &inner, &ignores),
+        Err(Error::BadName(_))
+    ));
+    assert!(!inner.exists());
+}
+
+#[test]
+fn test_merge() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    let mut store = InMemoryObjectStore::new();
+    for contents in ["a", "a'", "a''", "b", "d", "d'", "e", "e'", "f"] {
+        store.insert(contents.as_bytes()).unwrap();
+    }
+    let file =
+        |contents: &str| DirectoryEntry::File(ObjectId::from(contents.as_bytes()), Mode::REGULAR);
+    let dir = |entries: Vec<(&str, DirectoryEntry)>| Directory {
+        root: entries
+            .into_iter()
+            .map(|(name, entry)| (String::from(name), entry))
+            .collect(),
+    };
+    let c = dir(vec![("d", file("d"))]).insert(&mut store).unwrap();
+    let c_ours = dir(vec![("d", file("d")), ("e", file("e"))])
+        .insert(&mut store)
+        .unwrap();
+    let c_theirs = dir(vec![("d", file("d'"))]).insert(&mut store).unwrap();
+    let c_merged = dir(vec![("d", file("d'")), ("e", file("e"))]);
+    let c_merged = ObjectId::from(&serde_json::to_vec_pretty(&c_merged).unwrap());
+    let base = dir(vec![
+        ("a", file("a")),
+        ("b", file("b")),
+        ("c", DirectoryEntry::Directory(c)),
+    ]);
+    let ours = dir(vec![
+        ("a", file("a'")),
+        ("b", file("b")),
+        ("c", DirectoryEntry::Directory(c_ours)),
+    ]);
+    let theirs = dir(vec![
+        ("a", file("a'")),
+        ("c", DirectoryEntry::Directory(c_theirs)),
+        ("f", file("f")),
+    ]);
+    let merge = Directory::merge(&mut store, &base, &ours, &theirs).unwrap();
+    assert!(merge.conflicts.is_empty());
+    assert_eq!(
+        merge.directory,
+        dir(vec![
+            ("a", file("a'")),
+            ("c", DirectoryEntry::Directory(c_merged)),
+            ("f", file("f")),
+        ])
+    );
+    assert!(store.has(c_merged).unwrap());
+
+    let c_theirs = dir(vec![("d", file("d")), ("e", file("e'"))])
+        .insert(&mut store)
+        .unwrap();
+    let theirs = dir(vec![
+        ("a", file("a''")),
+        ("b", file("b")),
+        ("c", DirectoryEntry::Directory(c_theirs)),
+    ]);
+    let merge = Directory::merge(&mut store, &base, &ours, &theirs).unwrap();
+    assert_eq!(
+        merge.conflicts,
+        BTreeMap::from([
+            (PathBuf::from("a"), Conflict::Text),
+            (PathBuf::from("c/e"), Conflict::Text)
+        ])
+    );
+    assert_eq!(
+        merge.directory.root.get("a"),
+        Some(&file("<<<<<<< ours\na'\n=======\na''\n>>>>>>> theirs\n"))
+    );
+
+    let with_mode = |contents: &str, bits: u32| file(contents).with_mode(Mode::new(bits));
+    let base = dir(vec![("a", file("a")), ("b", file("b"))]);
+    let ours = dir(vec![("a", with_mode("a", 0o755)), ("b", file("b"))]);
+    let theirs = dir(vec![("a", with_mode("a'", 0o700)), ("b", file("b'"))]);
+    let merge = Directory::merge(&mut store, &base, &ours, &theirs).unwrap();
+    assert_eq!(
+        merge.conflicts,
+        BTreeMap::from([(PathBuf::from("a"), Conflict::Mode)])
+    );
+    assert_eq!(merge.directory.root["a"], with_mode("a'", 0o755));
+    assert_eq!(merge.conflicts[&PathBuf::from("a")].to_string(), "mode");
+
+    // Chunked files are never read to be merged line by line.
+    let chunked = |contents: &str| {
+        DirectoryEntry::Chunked(ObjectId::from(contents.as_bytes()), Mode::REGULAR)
+    };
+    let base = dir(vec![("big", chunked("big"))]);
+    let ours = dir(vec![("big", chunked("big'"))]);
+    let theirs = dir(vec![("big", chunked("big''"))]);
+    let merge = Directory::merge(&mut store, &base, &ours, &theirs).unwrap();
+    assert_eq!(
+        merge.conflicts,
+        BTreeMap::from([(PathBuf::from("big"), Conflict::Large)])
+    );
+    assert_eq!(merge.directory, ours);
+}
+
+#[test]
+fn test_shared_subtrees() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    use std::fs::{create_dir_all, write};
+    let tempdir = tempfile::tempdir().unwrap();
+    create_dir_all(tempdir.path().join("a")).unwrap();
+    create_dir_all(tempdir.path().join("b/c")).unwrap();
+    write(tempdir.path().join("a/x"), "x").unwrap();
+    write(tempdir.path().join("b/c/y"), "y").unwrap();
+    let ignores = Ignores::default();
+    let mut store = InMemoryObjectStore::new();
+    let before = Directory::new(tempdir.path(), &ignores, &mut store).unwrap();
+    write(tempdir.path().join("b/c/y"), "y'").unwrap();
+    let after = Directory::new(tempdir.path(), &ignores, &mut store).unwrap();
+    assert_eq!(before.root.get("a"), after.root.get("a"));
+    assert_ne!(before.root.get("b"), after.root.get("b"));
+    assert_eq!(
+        before.diff(&after, &store).unwrap().to_string(),
+        "M b/c/y\n"
+    );
+}
+
+#[test]
+fn test_chunked_files() {
+    use crate::{chunking::test_bytes, object_store::in_memory::InMemoryObjectStore};
+    use std::fs::{read, write};
+    let source = tempfile::tempdir().unwrap();
+    let target = tempfile::tempdir().unwrap();
+    let mut contents = test_bytes(3 * CHUNKED_FILE_SIZE);
+    write(source.path().join("big"), &contents).unwrap();
+    write(source.path().join("small"), "small").unwrap();
+    let ignores = Ignores::default();
+    let mut store = InMemoryObjectStore::new();
+    let before = Directory::new(source.path(), &ignores, &mut store).unwrap();
+    let Some(DirectoryEntry::Chunked(before_id, _)) = before.root.get("big").cloned() else {
+        panic!("big was not chunked");
+    };
+    assert!(matches!(
+        before.root.get("small"),
+        Some(DirectoryEntry::File(_, _))
+    ));
+
+    contents[CHUNKED_FILE_SIZE] ^= 1;
+    write(source.path().join("big"), &contents).unwrap();
+    let after = Directory::new(source.path(), &ignores, &mut store).unwrap();
+    assert_eq!(before.diff(&after, &store).unwrap().to_string(), "M big\n");
+    let chunk_list =
+        |id| -> ChunkList { serde_json::from_slice(&store.read(id).unwrap().unwrap()).unwrap() };
+    let Some(DirectoryEntry::Chunked(after_id, _)) = after.root.get("big").cloned() else {
+        panic!("big was not chunked");
+    };
+    let before_chunks = chunk_list(before_id).chunks;
+    let after_chunks = chunk_list(after_id).chunks;
+    let shared = after_chunks
+        .iter()
+        .filter(|chunk| before_chunks.contains(chunk))
+        .count();
+    assert!(shared + 1 >= after_chunks.len());
+
+    after.write(&store, target.path(), &ignores).unwrap();
+    assert_eq!(read(target.path().join("big")).unwrap(), contents);
+    assert_eq!(
+        DirectoryEntry::file(&contents),
+        DirectoryEntry::Chunked(after_id, Mode::REGULAR)
+    );
 }
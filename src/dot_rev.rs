@@ -1,23 +1,39 @@
 use std::{
-    collections::BTreeSet,
-    fs::{create_dir, create_dir_all, read_dir, read_to_string, try_exists, File},
-    io::Write,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    fmt,
+    fs::{create_dir_all, exists, read_dir, read_to_string, remove_file, File},
+    io::Read,
+    path::{absolute, Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
 };
 
 use derive_more::From;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    directory::{Directory, Ignores},
+    atomic,
+    config::{Config, STORE_BACKEND, STORE_COMPRESSION, STORE_VERIFY},
+    directory::{self, Conflict, Diff, Directory, Ignores},
+    history::{is_ancestor, merge_bases},
     object_id::ObjectId,
-    object_store::{directory::DirectoryObjectStore, ObjectStore},
-    snapshot::SnapShot,
+    object_store::{
+        codec::Codec, directory::DirectoryObjectStore, layered::LayeredObjectStore,
+        pack::PackObjectStore, Ids, ObjectStore, StreamError,
+    },
+    snapshot::{self, Signature, SnapShot},
 };
 
-/// A wrapper for the path of the .rev directory which has a number of utilities defined on it.
-pub struct DotRev {
+/// A wrapper for the path of the .rev directory which has a number of
+/// utilities defined on it, along with the [`ObjectStore`] holding the
+/// repository's objects.
+///
+/// Repositories on disk keep their objects in a [`RepositoryStore`], but
+/// any other store can be used, such as an in-memory one in tests.
+pub struct DotRev<Store = RepositoryStore> {
     root: PathBuf,
+    store: Store,
 }
 
 #[derive(Debug, From)]
@@ -27,69 +43,175 @@ pub enum Error {
     #[from]
     Serde(serde_json::Error),
     MissingObject(ObjectId),
+    /// A snapshot entry which is not a single file name, such as `..`.
+    BadName(String),
+    UnknownRevision(String),
+    /// A configuration key with a value which could not be parsed.
+    BadConfig(String, String),
+    /// No .rev directory was found in the given directory or its parents.
+    NoRepository(PathBuf),
+    NoBranch(String),
+    /// A merge is waiting for its conflicts to be resolved.
+    MergeInProgress,
+    /// Changes made since the latest snap, which would be overwritten.
+    UnsnappedChanges(Diff),
+    /// Changes made since the latest snap which conflict with the branch
+    /// being checked out.
+    Conflicts(BTreeMap<PathBuf, Conflict>),
 }
-impl DotRev {
-    pub fn root(&self) -> &PathBuf {
-        &self.root
+
+impl From<Infallible> for Error {
+    fn from(err: Infallible) -> Self {
+        match err {}
     }
+}
 
-    pub fn init(root: PathBuf) -> Result<Self, Error> {
-        if read_dir(&root).is_ok() {
-            return Ok(Self { root });
+impl<Store: ObjectStore> From<directory::Error<Store>> for Error
+where
+    Error: From<Store::Error>,
+{
+    fn from(err: directory::Error<Store>) -> Self {
+        match err {
+            directory::Error::ObjectMissing(id) => Error::MissingObject(id),
+            directory::Error::BadName(name) => Error::BadName(name),
+            directory::Error::Conflicts(conflicts) => Error::Conflicts(conflicts),
+            directory::Error::Store(err) => err.into(),
+            directory::Error::IO(err) => Error::IO(err),
+            directory::Error::Serde(err) => Error::Serde(err),
         }
-        create_dir_all(&root)?;
+    }
+}
 
-        // Start out on the dev branch
-        let mut file = File::options()
-            .create(true)
-            .write(true)
-            .open(&root.join("branch"))?;
-        file.write("dev".as_bytes())?;
+impl<Store: ObjectStore> From<snapshot::Error<Store>> for Error
+where
+    Error: From<Store::Error>,
+{
+    fn from(err: snapshot::Error<Store>) -> Self {
+        match err {
+            snapshot::Error::ObjectMissing(id) => Error::MissingObject(id),
+            snapshot::Error::Store(err) => err.into(),
+            snapshot::Error::Serde(err) => Error::Serde(err),
+            snapshot::Error::Directory(err) => err.into(),
+        }
+    }
+}
 
-        // Create the branches directory
-        create_dir(&root.join("branches"))?;
+/// How [`DotRev::checkout`] treats changes made since the latest snap.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CheckoutMode {
+    /// Refuse to overwrite them.
+    Clean,
+    /// Bring them over to the branch, unless they conflict with it.
+    Carry,
+    /// Discard them, along with any merge in progress.
+    Force,
+}
 
-        // Create the init commit on the dev branch
-        let mut store = DirectoryObjectStore::new(root.join("store"))?;
-        let directory = Directory::default();
-        let directory = store.insert_json(&directory)?;
-        let snapshot = SnapShot {
-            directory,
-            message: String::from("init"),
-            previous: BTreeSet::new(),
-        };
-        let snapshot_id = store.insert_json(&snapshot)?;
-        write_json(&snapshot_id, &root.join("branches").join("dev"))?;
-        let ignores = Ignores::default();
-        write_json(&ignores, &root.join("ignores"))?;
+/// What [`DotRev::merge`] did.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum MergeOutcome {
+    /// The branch was already an ancestor of the current one.
+    AlreadyMerged,
+    /// The current branch was an ancestor of the other one, and now points
+    /// at the same [`SnapShot`].
+    FastForward(ObjectId),
+    /// A new [`SnapShot`] with both tips as its previous ones.
+    Merged(ObjectId),
+    /// The merge was written out with these conflicts, and is finished by
+    /// the next snap.
+    Conflicts(BTreeMap<PathBuf, Conflict>),
+}
 
-        Ok(DotRev { root })
+impl DotRev {
+    /// Create a new .rev directory at the given path, starting out on the
+    /// given branch, or open the one which is already there.
+    pub fn init(root: PathBuf, default_branch: &str) -> Result<Self, Error> {
+        create_dir_all(&root)?;
+        let store = RepositoryStore::open(&root)?;
+        DotRev::init_with_store(root, default_branch, store)
     }
 
     pub fn existing(root: PathBuf) -> Result<Self, Error> {
         read_dir(&root)?;
-        Ok(DotRev { root })
+        let root = absolute(root)?;
+        let store = RepositoryStore::open(&root)?;
+        Ok(DotRev { root, store })
+    }
+
+    /// Find the .rev directory of the repository containing `start`, looking
+    /// in `start` itself and then in each of its parents.
+    pub fn discover(start: &Path) -> Result<Self, Error> {
+        let root = DotRev::discover_root(start)?;
+        let store = RepositoryStore::open(&root)?;
+        Ok(DotRev { root, store })
+    }
+
+    /// The path of the .rev directory [`DotRev::discover`] would open,
+    /// without opening its store.
+    pub fn discover_root(start: &Path) -> Result<PathBuf, Error> {
+        let start = absolute(start)?;
+        for dir in start.ancestors() {
+            let root = dir.join(".rev");
+            if root.is_dir() {
+                return Ok(root);
+            }
+        }
+        Err(Error::NoRepository(start))
+    }
+}
+
+impl<Store> DotRev<Store> {
+    /// Open the .rev directory at the given path, keeping objects in the
+    /// given store rather than the one configured.
+    pub fn with_store(root: PathBuf, store: Store) -> Self {
+        DotRev { root, store }
+    }
+
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut Store {
+        &mut self.store
+    }
+
+    /// The directory whose contents are snapped, which is the one holding
+    /// the .rev directory.
+    pub fn work_tree(&self) -> &Path {
+        self.root.parent().unwrap_or(&self.root)
     }
 
     pub fn branch(&self) -> Result<String, Error> {
-        Ok(read_to_string(&self.root.join("branch"))?)
+        Ok(read_to_string(self.root.join("branch"))?)
     }
 
     pub fn set_branch(&self, new_branch: &str) -> Result<(), Error> {
-        let mut file = File::options()
-            .write(true)
-            .truncate(true)
-            .open(&self.root.join("branch"))?;
-        file.write(new_branch.as_bytes())?;
-        Ok(())
+        Ok(atomic::write(
+            &self.root.join("branch"),
+            new_branch.as_bytes(),
+        )?)
     }
 
     pub fn branch_snapshot_id(&self, branch: &str) -> Result<ObjectId, Error> {
-        read_json(&self.root.join("branches").join(&branch))
+        read_json(&self.root.join("branches").join(branch))
     }
 
     pub fn set_branch_snapshot_id(&self, branch: &str, object_id: ObjectId) -> Result<(), Error> {
-        write_json(&object_id, &self.root.join("branches").join(&branch))
+        write_json(&object_id, &self.root.join("branches").join(branch))
+    }
+
+    /// Find the [`ObjectId`] of the [`SnapShot`] named by `rev`, which is
+    /// either a branch name or the [`ObjectId`] itself.
+    pub fn resolve(&self, rev: &str) -> Result<ObjectId, Error> {
+        if self.branch_exists(rev)? {
+            return self.branch_snapshot_id(rev);
+        }
+        rev.parse()
+            .map_err(|_| Error::UnknownRevision(String::from(rev)))
     }
 
     pub fn current_snapshot_id(&self) -> Result<ObjectId, Error> {
@@ -97,42 +219,515 @@ impl DotRev {
         self.branch_snapshot_id(&branch)
     }
 
+    /// The [`ObjectId`] of the [`SnapShot`] being merged into the current
+    /// branch, if a merge is waiting for its conflicts to be resolved.
+    pub fn merge_snapshot_id(&self) -> Result<Option<ObjectId>, Error> {
+        let path = self.root.join("merge");
+        if exists(&path)? {
+            Ok(Some(read_json(&path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn set_merge_snapshot_id(&self, object_id: Option<ObjectId>) -> Result<(), Error> {
+        let path = self.root.join("merge");
+        match object_id {
+            Some(object_id) => write_json(&object_id, &path),
+            None => {
+                if exists(&path)? {
+                    remove_file(&path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
     pub fn create_branch(&self, new_branch: &str) -> Result<(), Error> {
-        if !self.branch_exists(&new_branch)? {
+        if !self.branch_exists(new_branch)? {
             let snapshot_id = self.current_snapshot_id()?;
-            return write_json(&snapshot_id, &self.root.join("branches").join(&new_branch));
+            return write_json(&snapshot_id, &self.root.join("branches").join(new_branch));
         }
         Ok(())
     }
 
+    /// The names of all of the branches.
+    pub fn branches(&self) -> Result<Vec<String>, Error> {
+        let mut branches = Vec::new();
+        for entry in read_dir(self.root.join("branches"))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            // Names starting with a `.` are writes still in progress.
+            if !name.starts_with('.') {
+                branches.push(name);
+            }
+        }
+        branches.sort();
+        Ok(branches)
+    }
+
     pub fn branch_exists(&self, branch: &str) -> Result<bool, Error> {
-        Ok(try_exists(self.root.join("branches").join(&branch))?)
+        Ok(exists(self.root.join("branches").join(branch))?)
     }
 
-    pub fn store(&self) -> Result<DirectoryObjectStore, Error> {
-        Ok(DirectoryObjectStore::new(self.root.clone())?)
+    /// The layered configuration, including this repository's
+    /// `.rev/config`.
+    pub fn config(&self) -> Result<Config, Error> {
+        Config::load(Some(&self.root))
     }
 
     pub fn ignores(&self) -> Result<Ignores, Error> {
-        Ok(read_json(&self.root.join("ignores"))?)
+        read_json(&self.root.join("ignores"))
+    }
+}
+
+impl<Store: ObjectStore> DotRev<Store>
+where
+    Error: From<Store::Error>,
+{
+    /// Create a new .rev directory at the given path like [`DotRev::init`],
+    /// keeping objects in the given store.
+    pub fn init_with_store(
+        root: PathBuf,
+        default_branch: &str,
+        mut store: Store,
+    ) -> Result<Self, Error> {
+        if exists(root.join("branch"))? {
+            return Ok(Self { root, store });
+        }
+        create_dir_all(&root)?;
+
+        // Create the init commit on the default branch
+        let directory = Directory::default();
+        let directory = store.insert_json(&directory)?;
+        let snapshot = SnapShot {
+            directory,
+            message: String::from("init"),
+            previous: Vec::new(),
+            author: None,
+            committer: None,
+            trailers: BTreeMap::new(),
+        };
+        let snapshot_id = store.insert_json(&snapshot)?;
+        create_dir_all(root.join("branches"))?;
+        write_json(&snapshot_id, &root.join("branches").join(default_branch))?;
+        let ignores = Ignores::default();
+        write_json(&ignores, &root.join("ignores"))?;
+
+        // Start out on the default branch, which also marks the
+        // repository as complete
+        atomic::write(&root.join("branch"), default_branch.as_bytes())?;
+
+        Ok(DotRev { root, store })
+    }
+
+    /// The [`Directory`] of the work tree, with its files inserted into the
+    /// store.
+    pub fn working_directory(&mut self) -> Result<Directory, Error> {
+        let ignores = self.ignores()?;
+        let dir = self.work_tree().to_path_buf();
+        Ok(Directory::new(&dir, &ignores, &mut self.store)?)
+    }
+
+    /// The [`Directory`] of the [`SnapShot`] with the [`ObjectId`].
+    pub fn snapshot_directory(&self, id: ObjectId) -> Result<Directory, Error> {
+        let snapshot: SnapShot = self.store.read_json(id)?;
+        self.store.read_json(snapshot.directory)
+    }
+
+    /// What has changed in the work tree since the latest snap on the
+    /// current branch.
+    pub fn changes(&mut self) -> Result<Diff, Error> {
+        let old_directory = self.snapshot_directory(self.current_snapshot_id()?)?;
+        let directory = self.working_directory()?;
+        Ok(old_directory.diff(&directory, &self.store)?)
+    }
+
+    /// What differs between the latest snap on the current branch and the
+    /// one on another branch.
+    pub fn diff(&self, branch: &str) -> Result<Diff, Error> {
+        if !self.branch_exists(branch)? {
+            return Err(Error::NoBranch(String::from(branch)));
+        }
+        let this_directory = self.snapshot_directory(self.current_snapshot_id()?)?;
+        let that_directory = self.snapshot_directory(self.branch_snapshot_id(branch)?)?;
+        Ok(this_directory.diff(&that_directory, &self.store)?)
+    }
+
+    /// Take a new snapshot of the work tree on the current branch, which
+    /// finishes any merge in progress.
+    pub fn snap(
+        &mut self,
+        message: String,
+        author: Option<Signature>,
+        committer: Option<Signature>,
+        trailers: BTreeMap<String, String>,
+    ) -> Result<ObjectId, Error> {
+        let branch = self.branch()?;
+        let old_tip = self.branch_snapshot_id(&branch)?;
+        let directory = self.working_directory()?;
+        let directory_id = self.store.insert_json(&directory)?;
+        let merge_tip = self.merge_snapshot_id()?;
+        let snap = SnapShot {
+            directory: directory_id,
+            previous: vec![old_tip].into_iter().chain(merge_tip).collect(),
+            message,
+            author,
+            committer,
+            trailers,
+        };
+        let snap_id = self.store.insert_json(&snap)?;
+        self.set_branch_snapshot_id(&branch, snap_id)?;
+        self.set_merge_snapshot_id(None)?;
+        Ok(snap_id)
+    }
+
+    /// Switch to the branch, creating it at the current snapshot if it
+    /// does not exist yet, and write its latest snapshot to the work tree.
+    pub fn checkout(&mut self, branch: &str, mode: CheckoutMode) -> Result<(), Error> {
+        if self.merge_snapshot_id()?.is_some() {
+            if mode != CheckoutMode::Force {
+                return Err(Error::MergeInProgress);
+            }
+            self.set_merge_snapshot_id(None)?;
+        }
+        if !self.branch_exists(branch)? {
+            self.create_branch(branch)?;
+        }
+        let this_snapshot: SnapShot = self.store.read_json(self.current_snapshot_id()?)?;
+        let that_snapshot: SnapShot = self.store.read_json(self.branch_snapshot_id(branch)?)?;
+        if this_snapshot.directory != that_snapshot.directory {
+            let mut target: Directory = self.store.read_json(that_snapshot.directory)?;
+            if mode != CheckoutMode::Force {
+                let this_directory: Directory = self.store.read_json(this_snapshot.directory)?;
+                let working = self.working_directory()?;
+                if mode == CheckoutMode::Carry {
+                    let merge =
+                        Directory::merge(&mut self.store, &this_directory, &target, &working)?;
+                    if !merge.conflicts.is_empty() {
+                        return Err(Error::Conflicts(merge.conflicts));
+                    }
+                    target = merge.directory;
+                } else {
+                    let diff = this_directory.diff(&working, &self.store)?;
+                    if !diff.is_empty() {
+                        return Err(Error::UnsnappedChanges(diff));
+                    }
+                }
+            }
+            target.write(&self.store, self.work_tree(), &self.ignores()?)?;
+        }
+        self.set_branch(branch)
+    }
+
+    /// Merge the branch into the current one, writing the result to the
+    /// work tree. Without conflicts, this snaps the merge with the given
+    /// message, or one naming both branches.
+    pub fn merge(
+        &mut self,
+        branch: &str,
+        message: Option<String>,
+        author: Option<Signature>,
+        committer: Option<Signature>,
+    ) -> Result<MergeOutcome, Error> {
+        let that_branch = branch;
+        let this_branch = self.branch()?;
+        if !self.branch_exists(that_branch)? {
+            return Err(Error::NoBranch(String::from(that_branch)));
+        }
+        if self.merge_snapshot_id()?.is_some() {
+            return Err(Error::MergeInProgress);
+        }
+        let this_tip = self.branch_snapshot_id(&this_branch)?;
+        let that_tip = self.branch_snapshot_id(that_branch)?;
+        let this_directory = self.snapshot_directory(this_tip)?;
+        let that_directory = self.snapshot_directory(that_tip)?;
+        let ignores = self.ignores()?;
+        let working = self.working_directory()?;
+        let diff = this_directory.diff(&working, &self.store)?;
+        if !diff.is_empty() {
+            return Err(Error::UnsnappedChanges(diff));
+        }
+        if is_ancestor(&self.store, that_tip, this_tip)? {
+            return Ok(MergeOutcome::AlreadyMerged);
+        }
+        if is_ancestor(&self.store, this_tip, that_tip)? {
+            that_directory.write(&self.store, self.work_tree(), &ignores)?;
+            self.set_branch_snapshot_id(&this_branch, that_tip)?;
+            return Ok(MergeOutcome::FastForward(that_tip));
+        }
+        let bases = merge_bases(&self.store, &[this_tip, that_tip])?;
+        let base_directory = self.base_directory(&bases)?;
+        let merge = Directory::merge(
+            &mut self.store,
+            &base_directory,
+            &this_directory,
+            &that_directory,
+        )?;
+        merge
+            .directory
+            .write(&self.store, self.work_tree(), &ignores)?;
+        if !merge.conflicts.is_empty() {
+            self.set_merge_snapshot_id(Some(that_tip))?;
+            return Ok(MergeOutcome::Conflicts(merge.conflicts));
+        }
+        let directory_id = self.store.insert_json(&merge.directory)?;
+        let snap = SnapShot {
+            directory: directory_id,
+            previous: vec![this_tip, that_tip],
+            message: message
+                .unwrap_or_else(|| format!("merge {} into {}", that_branch, this_branch)),
+            author,
+            committer,
+            trailers: BTreeMap::new(),
+        };
+        let snap_id = self.store.insert_json(&snap)?;
+        self.set_branch_snapshot_id(&this_branch, snap_id)?;
+        Ok(MergeOutcome::Merged(snap_id))
+    }
+
+    /// The [`Directory`] to use as the base of a merge with the given merge
+    /// bases. When there are several, as after criss-cross merges, they are
+    /// merged with each other first, conflict markers and all.
+    fn base_directory(&mut self, bases: &BTreeSet<ObjectId>) -> Result<Directory, Error> {
+        let mut bases = bases.iter();
+        let Some(first) = bases.next() else {
+            return Ok(Directory::default());
+        };
+        let mut directory = self.snapshot_directory(*first)?;
+        for other in bases {
+            let other_directory = self.snapshot_directory(*other)?;
+            let bases = merge_bases(&self.store, &[*first, *other])?;
+            let base = self.base_directory(&bases)?;
+            directory =
+                Directory::merge(&mut self.store, &base, &directory, &other_directory)?.directory;
+        }
+        Ok(directory)
+    }
+
+    /// Rewrite the [`SnapShot`]s whose directory trees were written with
+    /// subdirectories nested inside them, as they were before each
+    /// subdirectory became its own object, along with every snapshot
+    /// after them, and move the branches and any merge in progress to the
+    /// rewritten snapshots. Returns how many snapshots were rewritten.
+    pub fn migrate(&mut self) -> Result<usize, Error> {
+        let mut tips = BTreeMap::new();
+        for branch in self.branches()? {
+            tips.insert(branch.clone(), self.branch_snapshot_id(&branch)?);
+        }
+        let merge = self.merge_snapshot_id()?;
+        let mut migrated: BTreeMap<ObjectId, ObjectId> = BTreeMap::new();
+        let mut stack: Vec<ObjectId> = tips.values().copied().chain(merge).collect();
+        // Each snapshot is rewritten once all of its previous ones have
+        // been, since its previous ones' ids may change.
+        while let Some(&id) = stack.last() {
+            if migrated.contains_key(&id) {
+                stack.pop();
+                continue;
+            }
+            let snapshot: SnapShot = self.store.read_json(id)?;
+            let pending: Vec<ObjectId> = snapshot
+                .previous
+                .iter()
+                .copied()
+                .filter(|previous| !migrated.contains_key(previous))
+                .collect();
+            if !pending.is_empty() {
+                stack.extend(pending);
+                continue;
+            }
+            stack.pop();
+            let rewritten = SnapShot {
+                directory: Directory::migrate(&mut self.store, snapshot.directory)?,
+                previous: snapshot
+                    .previous
+                    .iter()
+                    .map(|previous| migrated[previous])
+                    .collect(),
+                ..snapshot.clone()
+            };
+            let new_id = if rewritten == snapshot {
+                id
+            } else {
+                self.store.insert_json(&rewritten)?
+            };
+            migrated.insert(id, new_id);
+        }
+        for (branch, id) in tips {
+            self.set_branch_snapshot_id(&branch, migrated[&id])?;
+        }
+        if let Some(id) = merge {
+            self.set_merge_snapshot_id(Some(migrated[&id]))?;
+        }
+        Ok(migrated.iter().filter(|(id, new_id)| id != new_id).count())
+    }
+}
+
+/// Where a repository keeps its objects, set with `store.backend`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Backend {
+    /// New objects are written loose into `.rev/store`, and `revtool
+    /// repack` moves them into `.rev/packs`.
+    #[default]
+    Packed,
+    /// Every object is kept loose in `.rev/store`.
+    Loose,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Packed => write!(f, "packed"),
+            Backend::Loose => write!(f, "loose"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "packed" => Ok(Backend::Packed),
+            "loose" => Ok(Backend::Loose),
+            _ => Err(format!("unknown backend {}", s)),
+        }
+    }
+}
+
+/// The objects of a repository on disk, kept as its [`Backend`] says.
+#[derive(Debug)]
+pub enum RepositoryStore {
+    Packed(LayeredObjectStore<DirectoryObjectStore, PackObjectStore>),
+    Loose(DirectoryObjectStore),
+}
+
+impl RepositoryStore {
+    /// The objects of the .rev directory, kept as `store.backend` says,
+    /// with loose objects compressed as set by `store.compression` and
+    /// reads checked if `store.verify` is set.
+    pub fn open(root: &Path) -> Result<Self, Error> {
+        let config = Config::load(Some(root))?;
+        let backend: Backend = config.get_parsed(STORE_BACKEND)?.unwrap_or_default();
+        let codec: Codec = config.get_parsed(STORE_COMPRESSION)?.unwrap_or_default();
+        let verify: bool = config.get_parsed(STORE_VERIFY)?.unwrap_or_default();
+        let loose = DirectoryObjectStore::new(root.join("store"))?
+            .with_codec(codec)
+            .with_verify(verify);
+        Ok(match backend {
+            Backend::Packed => RepositoryStore::Packed(LayeredObjectStore::new(
+                loose,
+                PackObjectStore::new(root.join("packs"))?.with_verify(verify),
+            )),
+            Backend::Loose => RepositoryStore::Loose(loose),
+        })
+    }
+
+    /// The loose objects, where new objects are written.
+    pub fn loose(&mut self) -> &mut DirectoryObjectStore {
+        match self {
+            RepositoryStore::Packed(store) => store.upper(),
+            RepositoryStore::Loose(store) => store,
+        }
+    }
+
+    /// The packs loose objects are moved into, unless every object is kept
+    /// loose.
+    pub fn packs(&mut self) -> Option<&mut PackObjectStore> {
+        match self {
+            RepositoryStore::Packed(store) => Some(store.lower()),
+            RepositoryStore::Loose(_) => None,
+        }
     }
 }
 
-/// A convenience trait for writing and reading JSON from the [`DirectoryObjectStore`].
+impl ObjectStore for RepositoryStore {
+    type Error = std::io::Error;
+
+    fn has(&self, id: ObjectId) -> Result<bool, Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.has(id),
+            RepositoryStore::Loose(store) => store.has(id),
+        }
+    }
+
+    fn read(&self, id: ObjectId) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.read(id),
+            RepositoryStore::Loose(store) => store.read(id),
+        }
+    }
+
+    fn insert(&mut self, object: &[u8]) -> Result<ObjectId, Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.insert(object),
+            RepositoryStore::Loose(store) => store.insert(object),
+        }
+    }
+
+    fn ids(&self) -> Ids<'_, Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.ids(),
+            RepositoryStore::Loose(store) => store.ids(),
+        }
+    }
+
+    fn remove(&mut self, id: ObjectId) -> Result<(), Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.remove(id),
+            RepositoryStore::Loose(store) => store.remove(id),
+        }
+    }
+
+    fn modified(&self, id: ObjectId) -> Result<Option<SystemTime>, Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.modified(id),
+            RepositoryStore::Loose(store) => store.modified(id),
+        }
+    }
+
+    fn size_of(&self, id: ObjectId) -> Result<Option<u64>, Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.size_of(id),
+            RepositoryStore::Loose(store) => store.size_of(id),
+        }
+    }
+
+    fn insert_stream(
+        &mut self,
+        reader: &mut dyn Read,
+    ) -> Result<ObjectId, StreamError<Self::Error>> {
+        match self {
+            RepositoryStore::Packed(store) => store.insert_stream(reader),
+            RepositoryStore::Loose(store) => store.insert_stream(reader),
+        }
+    }
+
+    fn read_stream(&self, id: ObjectId) -> Result<Option<Box<dyn Read + '_>>, Self::Error> {
+        match self {
+            RepositoryStore::Packed(store) => store.read_stream(id),
+            RepositoryStore::Loose(store) => store.read_stream(id),
+        }
+    }
+}
+
+/// A convenience trait for writing and reading JSON from an [`ObjectStore`].
 pub trait InsertJson {
     /// Inserts a pretty JSON encoded version of the thing into the store.
     fn insert_json<A: Serialize>(&mut self, thing: &A) -> Result<ObjectId, Error>;
 
     /// Reads a JSON encoded thing of the given type from the store at that given [`ObjectId`].
-    fn read_json<A: for<'de> Deserialize<'de>>(&mut self, object_id: ObjectId) -> Result<A, Error>;
+    fn read_json<A: for<'de> Deserialize<'de>>(&self, object_id: ObjectId) -> Result<A, Error>;
 }
 
-impl InsertJson for DirectoryObjectStore {
+impl<Store: ObjectStore> InsertJson for Store
+where
+    Error: From<Store::Error>,
+{
     fn insert_json<A: Serialize>(&mut self, thing: &A) -> Result<ObjectId, Error> {
         Ok(self.insert(&serde_json::to_vec_pretty(thing)?)?)
     }
 
-    fn read_json<A: for<'de> Deserialize<'de>>(&mut self, object_id: ObjectId) -> Result<A, Error> {
+    fn read_json<A: for<'de> Deserialize<'de>>(&self, object_id: ObjectId) -> Result<A, Error> {
         match self.read(object_id)? {
             None => Err(Error::MissingObject(object_id)),
             Some(obj) => Ok(serde_json::from_slice(&obj)?),
@@ -140,15 +735,132 @@ impl InsertJson for DirectoryObjectStore {
     }
 }
 
-fn read_json<A: for<'de> Deserialize<'de>>(path: &Path) -> Result<A, Error> {
+pub(crate) fn read_json<A: for<'de> Deserialize<'de>>(path: &Path) -> Result<A, Error> {
     Ok(serde_json::from_reader(
         File::options().read(true).open(path)?,
     )?)
 }
 
-fn write_json<A: Serialize>(thing: &A, path: &Path) -> Result<(), Error> {
-    Ok(serde_json::to_writer_pretty(
-        File::options().write(true).create(true).open(path)?,
-        thing,
-    )?)
+/// Replace the file at the path with the thing as pretty JSON, atomically,
+/// so that a crash leaves either the old value or the new one.
+pub(crate) fn write_json<A: Serialize>(thing: &A, path: &Path) -> Result<(), Error> {
+    Ok(atomic::write(path, &serde_json::to_vec_pretty(thing)?)?)
+}
+
+#[test]
+fn test_discover() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let work_tree = tempdir.path().join("work");
+    let nested = work_tree.join("a").join("b");
+    create_dir_all(&nested).unwrap();
+    assert!(matches!(
+        DotRev::discover(&nested),
+        Err(Error::NoRepository(_))
+    ));
+    DotRev::init(work_tree.join(".rev"), "dev").unwrap();
+    let dot_rev = DotRev::discover(&nested).unwrap();
+    assert_eq!(dot_rev.root(), &work_tree.join(".rev"));
+    assert_eq!(dot_rev.work_tree(), work_tree.as_path());
+}
+
+#[test]
+fn test_in_memory_repository() {
+    use crate::object_store::in_memory::InMemoryObjectStore;
+    use std::fs::{read_to_string, write};
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let work_tree = tempdir.path();
+    let mut dot_rev =
+        DotRev::init_with_store(work_tree.join(".rev"), "dev", InMemoryObjectStore::new()).unwrap();
+    assert!(!exists(work_tree.join(".rev").join("store")).unwrap());
+    write(work_tree.join("file"), "one\ntwo\nthree\n").unwrap();
+    assert_eq!(dot_rev.changes().unwrap().added.len(), 1);
+    let first = dot_rev
+        .snap(String::from("first"), None, None, BTreeMap::new())
+        .unwrap();
+    assert!(dot_rev.changes().unwrap().is_empty());
+
+    dot_rev.checkout("feature", CheckoutMode::Clean).unwrap();
+    write(work_tree.join("file"), "one\ntwo\nthree\nfour\n").unwrap();
+    dot_rev
+        .snap(String::from("feature"), None, None, BTreeMap::new())
+        .unwrap();
+    assert_eq!(dot_rev.diff("dev").unwrap().modified.len(), 1);
+
+    dot_rev.checkout("dev", CheckoutMode::Clean).unwrap();
+    assert_eq!(
+        read_to_string(work_tree.join("file")).unwrap(),
+        "one\ntwo\nthree\n"
+    );
+    write(work_tree.join("file"), "zero\none\ntwo\nthree\n").unwrap();
+    assert!(matches!(
+        dot_rev.checkout("feature", CheckoutMode::Clean),
+        Err(Error::UnsnappedChanges(_))
+    ));
+    dot_rev
+        .snap(String::from("second"), None, None, BTreeMap::new())
+        .unwrap();
+
+    let MergeOutcome::Merged(merged) = dot_rev.merge("feature", None, None, None).unwrap() else {
+        panic!("expected a merge snapshot");
+    };
+    assert_eq!(
+        read_to_string(work_tree.join("file")).unwrap(),
+        "zero\none\ntwo\nthree\nfour\n"
+    );
+    let snapshot: SnapShot = dot_rev.store().read_json(merged).unwrap();
+    assert_eq!(snapshot.message, "merge feature into dev");
+    assert!(is_ancestor(dot_rev.store(), first, merged).unwrap());
+    assert_eq!(
+        dot_rev.merge("feature", None, None, None).unwrap(),
+        MergeOutcome::AlreadyMerged
+    );
+}
+
+#[test]
+fn test_migrate() {
+    use crate::{directory::DirectoryEntry, object_store::in_memory::InMemoryObjectStore};
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut dot_rev = DotRev::init_with_store(
+        tempdir.path().join(".rev"),
+        "dev",
+        InMemoryObjectStore::new(),
+    )
+    .unwrap();
+    let init = dot_rev.current_snapshot_id().unwrap();
+    let file = dot_rev.store_mut().insert(b"file").unwrap();
+    let nested = format!(
+        r#"{{"a": {{"Directory": {{"b": {{"File": "{file}"}}}}}}, "c": {{"File": "{file}"}}}}"#
+    );
+    let directory = dot_rev.store_mut().insert(nested.as_bytes()).unwrap();
+    let old = dot_rev
+        .store_mut()
+        .insert_json(&SnapShot {
+            message: String::from("nested"),
+            directory,
+            previous: vec![init],
+            author: None,
+            committer: None,
+            trailers: BTreeMap::new(),
+        })
+        .unwrap();
+    dot_rev.set_branch_snapshot_id("dev", old).unwrap();
+    assert!(dot_rev.snapshot_directory(old).is_err());
+
+    assert_eq!(dot_rev.migrate().unwrap(), 1);
+    let new = dot_rev.current_snapshot_id().unwrap();
+    assert_ne!(new, old);
+    let snapshot: SnapShot = dot_rev.store().read_json(new).unwrap();
+    assert_eq!(snapshot.previous, vec![init]);
+    let directory = dot_rev.snapshot_directory(new).unwrap();
+    assert_eq!(
+        directory.root["c"],
+        DirectoryEntry::File(file, directory::Mode::REGULAR)
+    );
+    assert_eq!(
+        directory.entry(dot_rev.store(), Path::new("a/b")).unwrap(),
+        Some(DirectoryEntry::File(file, directory::Mode::REGULAR))
+    );
+    assert_eq!(dot_rev.migrate().unwrap(), 0);
 }
@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Deserialize;
+
+use crate::{
+    directory::{ChunkList, Directory, DirectoryEntry},
+    object_id::ObjectId,
+    object_store::ObjectStore,
+    snapshot::SnapShot,
+};
+
+/// The problems found by [`check`].
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Report {
+    /// Objects which something reachable refers to, but which are not in
+    /// the store.
+    pub missing: BTreeSet<ObjectId>,
+    /// Objects which could not be read, do not hash to their [`ObjectId`],
+    /// or are not what refers to them expects them to be.
+    pub corrupt: BTreeSet<ObjectId>,
+    /// Objects in the store which nothing reachable refers to.
+    pub dangling: BTreeSet<ObjectId>,
+    /// References which do not point at a [`SnapShot`], with the reason.
+    pub bad_refs: BTreeMap<String, String>,
+}
+
+impl Report {
+    /// Whether nothing is missing, corrupt or pointing at the wrong thing.
+    /// Dangling objects are harmless.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty() && self.bad_refs.is_empty()
+    }
+}
+
+/// What an object is expected to be by whatever refers to it.
+#[derive(Clone, Copy)]
+pub(crate) enum Kind {
+    SnapShot,
+    Directory,
+    ChunkList,
+    File,
+}
+
+/// Check the store: rehash every one of the objects `ids`, and parse every
+/// [`SnapShot`], [`Directory`] and [`ChunkList`] reachable from the named
+/// references, which are `None` where the reference itself is unreadable.
+pub fn check<Store: ObjectStore>(
+    store: &Store,
+    ids: impl IntoIterator<Item = ObjectId>,
+    refs: &BTreeMap<String, Option<ObjectId>>,
+) -> Report {
+    let mut report = Report::default();
+    let ids: BTreeSet<ObjectId> = ids.into_iter().collect();
+    for id in &ids {
+        match store.read(*id) {
+            Ok(Some(object)) if ObjectId::from(&object) == *id => {}
+            _ => {
+                report.corrupt.insert(*id);
+            }
+        }
+    }
+
+    let mut reachable = BTreeSet::new();
+    let mut stack = Vec::new();
+    for (name, id) in refs {
+        let Some(id) = id else {
+            report
+                .bad_refs
+                .insert(name.clone(), String::from("unreadable"));
+            continue;
+        };
+        match store.read(*id) {
+            Ok(Some(object)) => {
+                if serde_json::from_slice::<SnapShot>(&object).is_ok() {
+                    stack.push((*id, Kind::SnapShot));
+                } else {
+                    report
+                        .bad_refs
+                        .insert(name.clone(), format!("{} is not a snapshot", id));
+                    reachable.insert(*id);
+                }
+            }
+            Ok(None) => {
+                report
+                    .bad_refs
+                    .insert(name.clone(), format!("{} is missing", id));
+                report.missing.insert(*id);
+            }
+            Err(_) => {
+                report
+                    .bad_refs
+                    .insert(name.clone(), format!("{} is unreadable", id));
+                report.corrupt.insert(*id);
+            }
+        }
+    }
+
+    while let Some((id, kind)) = stack.pop() {
+        if !reachable.insert(id) {
+            continue;
+        }
+        let object = match store.read(id) {
+            Ok(Some(object)) => object,
+            Ok(None) => {
+                report.missing.insert(id);
+                continue;
+            }
+            Err(_) => {
+                report.corrupt.insert(id);
+                continue;
+            }
+        };
+        match references(kind, &object) {
+            Some(children) => stack.extend(children),
+            None => {
+                report.corrupt.insert(id);
+            }
+        }
+    }
+
+    report.dangling = ids.difference(&reachable).copied().collect();
+    report
+}
+
+/// The objects which the object refers to, or `None` if it is not the
+/// kind of object expected.
+pub(crate) fn references(kind: Kind, object: &[u8]) -> Option<Vec<(ObjectId, Kind)>> {
+    match kind {
+        Kind::File => Some(Vec::new()),
+        Kind::SnapShot => parse(object).map(|snapshot: SnapShot| {
+            let mut children = vec![(snapshot.directory, Kind::Directory)];
+            children.extend(snapshot.previous.iter().map(|id| (*id, Kind::SnapShot)));
+            children
+        }),
+        Kind::Directory => parse(object).map(|directory: Directory| {
+            directory
+                .root
+                .values()
+                .filter_map(|entry| match entry {
+                    DirectoryEntry::Directory(id) => Some((*id, Kind::Directory)),
+                    DirectoryEntry::File(id, _) => Some((*id, Kind::File)),
+                    DirectoryEntry::Chunked(id, _) => Some((*id, Kind::ChunkList)),
+                    DirectoryEntry::Symlink(_) => None,
+                })
+                .collect()
+        }),
+        Kind::ChunkList => parse(object).map(|chunk_list: ChunkList| {
+            chunk_list
+                .chunks
+                .iter()
+                .map(|id| (*id, Kind::File))
+                .collect()
+        }),
+    }
+}
+
+fn parse<A: for<'de> Deserialize<'de>>(object: &[u8]) -> Option<A> {
+    serde_json::from_slice(object).ok()
+}
+
+#[test]
+fn test_check() {
+    use crate::{object_store::directory::DirectoryObjectStore, snapshot::test_snap};
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut store = DirectoryObjectStore::new(tempdir.path().into()).unwrap();
+    let file = store.insert(b"contents").unwrap();
+    let lost = store.insert(b"lost").unwrap();
+    let dangling = store.insert(b"dangling").unwrap();
+    let files: [(&str, &[u8]); 2] = [("file", b"contents"), ("lost", b"lost")];
+    let snapshot = test_snap(&mut store, "snap", &files, Vec::new(), None);
+    let s = format!("{}", file);
+    std::fs::write(tempdir.path().join(&s[0..2]).join(&s[2..]), "corrupted").unwrap();
+    store.remove(lost).unwrap();
+
+    let refs = BTreeMap::from([
+        (String::from("dev"), Some(snapshot)),
+        (String::from("file"), Some(file)),
+        (String::from("broken"), None),
+    ]);
+    let report = check(&store, store.ids().map(Result::unwrap), &refs);
+    assert!(!report.is_ok());
+    assert_eq!(report.missing, BTreeSet::from([lost]));
+    assert_eq!(report.corrupt, BTreeSet::from([file]));
+    assert_eq!(report.dangling, BTreeSet::from([dangling]));
+    assert_eq!(
+        report.bad_refs.keys().collect::<Vec<_>>(),
+        vec!["broken", "file"]
+    );
+}
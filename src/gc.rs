@@ -0,0 +1,156 @@
+use std::{collections::BTreeSet, time::SystemTime};
+
+use crate::{
+    fsck::{references, Kind},
+    object_id::ObjectId,
+    object_store::ObjectStore,
+};
+
+#[derive(Debug)]
+pub enum Error<Store: ObjectStore> {
+    ObjectMissing(ObjectId),
+    /// An object which is not the kind of object that refers to it expects.
+    ObjectCorrupt(ObjectId),
+    Store(Store::Error),
+}
+
+/// Every object reachable from the given [`SnapShot`](crate::snapshot::SnapShot)s.
+///
+/// This fails on objects which are missing or cannot be parsed rather
+/// than skipping them, since whatever they refer to would then be swept.
+pub fn mark<Store: ObjectStore>(
+    store: &Store,
+    roots: impl IntoIterator<Item = ObjectId>,
+) -> Result<BTreeSet<ObjectId>, Error<Store>> {
+    let mut live = BTreeSet::new();
+    let mut stack: Vec<(ObjectId, Kind)> =
+        roots.into_iter().map(|id| (id, Kind::SnapShot)).collect();
+    while let Some((id, kind)) = stack.pop() {
+        if !live.insert(id) {
+            continue;
+        }
+        // File contents and chunks refer to nothing, so only their presence
+        // matters; reading them would mean reading every blob in full.
+        if let Kind::File = kind {
+            if !store.has(id).map_err(Error::Store)? {
+                return Err(Error::ObjectMissing(id));
+            }
+            continue;
+        }
+        let object = store
+            .read(id)
+            .map_err(Error::Store)?
+            .ok_or(Error::ObjectMissing(id))?;
+        stack.extend(references(kind, &object).ok_or(Error::ObjectCorrupt(id))?);
+    }
+    Ok(live)
+}
+
+/// Remove every object which is not `live` and was last written before
+/// `cutoff`, returning the ones removed.
+///
+/// The cutoff leaves alone objects which were written for a snapshot that
+/// is still being taken, and so are not reachable yet.
+pub fn sweep<Store: ObjectStore>(
+    store: &mut Store,
+    live: &BTreeSet<ObjectId>,
+    cutoff: SystemTime,
+) -> Result<Vec<ObjectId>, Store::Error> {
+    let ids: Vec<ObjectId> = store.ids().collect::<Result<_, _>>()?;
+    let mut removed = Vec::new();
+    for id in ids {
+        if live.contains(&id) {
+            continue;
+        }
+        if store
+            .modified(id)?
+            .is_some_and(|modified| modified > cutoff)
+        {
+            continue;
+        }
+        store.remove(id)?;
+        removed.push(id);
+    }
+    Ok(removed)
+}
+
+#[test]
+fn test_gc() {
+    use crate::{
+        object_store::{
+            directory::DirectoryObjectStore, in_memory::InMemoryObjectStore,
+            layered::LayeredObjectStore, pack::PackObjectStore,
+        },
+        snapshot::test_snap,
+    };
+    use std::time::Duration;
+
+    fn snap<Store: ObjectStore>(store: &mut Store, contents: &[u8]) -> ObjectId
+    where
+        Store::Error: std::fmt::Debug,
+    {
+        test_snap(store, "snap", &[("file", contents)], Vec::new(), None)
+    }
+
+    let mut store = InMemoryObjectStore::new();
+    let kept = snap(&mut store, b"kept");
+    let abandoned = snap(&mut store, b"abandoned");
+    let live = mark(&store, [kept]).unwrap();
+    assert_eq!(live.len(), 3);
+    let removed = sweep(&mut store, &live, SystemTime::now()).unwrap();
+    assert_eq!(removed.len(), 3);
+    assert!(removed.contains(&abandoned));
+    assert_eq!(store.ids().count(), 3);
+    assert!(matches!(
+        mark(&store, [abandoned]),
+        Err(Error::ObjectMissing(id)) if id == abandoned
+    ));
+
+    // File contents are only checked for, but must still be there.
+    let blob = ObjectId::from(&b"kept".to_vec());
+    store.remove(blob).unwrap();
+    assert!(matches!(
+        mark(&store, [kept]),
+        Err(Error::ObjectMissing(id)) if id == blob
+    ));
+
+    // Objects newer than the cutoff are kept.
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut store = DirectoryObjectStore::new(tempdir.path().into()).unwrap();
+    snap(&mut store, b"abandoned");
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    assert!(sweep(&mut store, &BTreeSet::new(), an_hour_ago)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        sweep(&mut store, &BTreeSet::new(), SystemTime::now())
+            .unwrap()
+            .len(),
+        3
+    );
+
+    // Reusing a packed object counts as writing it again, so a gc which
+    // started just before does not sweep it, even with no grace period.
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut packs = PackObjectStore::new(tempdir.path().join("packs")).unwrap();
+    let reused = packs.insert(b"reused").unwrap();
+    packs.flush().unwrap();
+    for entry in std::fs::read_dir(tempdir.path().join("packs")).unwrap() {
+        std::fs::File::options()
+            .write(true)
+            .open(entry.unwrap().path())
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+    }
+    let loose = DirectoryObjectStore::new(tempdir.path().join("store")).unwrap();
+    let mut store = LayeredObjectStore::new(loose, packs);
+    let cutoff = SystemTime::now();
+    // File times come from a coarser clock than SystemTime::now.
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.insert(b"reused").unwrap(), reused);
+    assert!(sweep(&mut store, &BTreeSet::new(), cutoff)
+        .unwrap()
+        .is_empty());
+    assert!(store.has(reused).unwrap());
+}
@@ -0,0 +1,512 @@
+use std::{
+    cmp::Ordering,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use crate::{
+    directory::{Directory, DirectoryEntry},
+    object_id::ObjectId,
+    object_store::ObjectStore,
+    snapshot::{Error, SnapShot},
+};
+
+/// All of the [`SnapShot`]s reachable from the given ones through their
+/// previous snapshots, including the given ones themselves.
+pub fn ancestors<Store: ObjectStore>(
+    store: &Store,
+    ids: impl IntoIterator<Item = ObjectId>,
+) -> Result<BTreeSet<ObjectId>, Error<Store>> {
+    let mut seen = BTreeSet::new();
+    let mut stack: Vec<ObjectId> = ids.into_iter().collect();
+    while let Some(id) = stack.pop() {
+        if seen.insert(id) {
+            stack.extend(SnapShot::read(store, id)?.previous);
+        }
+    }
+    Ok(seen)
+}
+
+/// Whether `a` is an ancestor of `b`. Every [`SnapShot`] is an ancestor of
+/// itself.
+pub fn is_ancestor<Store: ObjectStore>(
+    store: &Store,
+    a: ObjectId,
+    b: ObjectId,
+) -> Result<bool, Error<Store>> {
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![b];
+    while let Some(id) = stack.pop() {
+        if id == a {
+            return Ok(true);
+        }
+        if seen.insert(id) {
+            stack.extend(SnapShot::read(store, id)?.previous);
+        }
+    }
+    Ok(false)
+}
+
+/// The best common ancestors of the given [`SnapShot`]s: those which are
+/// ancestors of all of them, but not ancestors of another such common
+/// ancestor.
+///
+/// There can be more than one, for instance after criss-cross merges, and
+/// there are none if the snapshots share no history.
+pub fn merge_bases<Store: ObjectStore>(
+    store: &Store,
+    ids: &[ObjectId],
+) -> Result<BTreeSet<ObjectId>, Error<Store>> {
+    let mut ids = ids.iter();
+    let mut common = match ids.next() {
+        Some(id) => ancestors(store, [*id])?,
+        None => return Ok(BTreeSet::new()),
+    };
+    for id in ids {
+        let theirs = ancestors(store, [*id])?;
+        common.retain(|id| theirs.contains(id));
+    }
+    let mut previous = Vec::new();
+    for id in common.iter() {
+        previous.extend(SnapShot::read(store, *id)?.previous);
+    }
+    let redundant = ancestors(store, previous)?;
+    common.retain(|id| !redundant.contains(id));
+    Ok(common)
+}
+
+/// For the files and directories of every [`SnapShot`] reachable from the
+/// given tips, the earlier version at the same path in its first previous
+/// snapshot, where that differs. These are good bases for storing the
+/// objects as deltas.
+pub fn previous_versions<Store: ObjectStore>(
+    store: &Store,
+    tips: impl IntoIterator<Item = ObjectId>,
+) -> Result<BTreeMap<ObjectId, ObjectId>, Error<Store>> {
+    let mut versions = BTreeMap::new();
+    for id in ancestors(store, tips)? {
+        let snapshot = SnapShot::read(store, id)?;
+        if let Some(previous) = snapshot.previous.first() {
+            let previous = SnapShot::read(store, *previous)?;
+            pair_versions(store, snapshot.directory, previous.directory, &mut versions)
+                .map_err(Error::Directory)?;
+        }
+    }
+    Ok(versions)
+}
+
+fn pair_versions<Store: ObjectStore>(
+    store: &Store,
+    new: ObjectId,
+    old: ObjectId,
+    versions: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Result<(), crate::directory::Error<Store>> {
+    if new == old || versions.contains_key(&new) {
+        return Ok(());
+    }
+    versions.insert(new, old);
+    let old = Directory::read(store, old)?;
+    for (name, entry) in Directory::read(store, new)?.root {
+        match (entry, old.root.get(&name)) {
+            (DirectoryEntry::File(new, _), Some(DirectoryEntry::File(old, _)))
+            | (DirectoryEntry::Chunked(new, _), Some(DirectoryEntry::Chunked(old, _)))
+                if new != *old =>
+            {
+                versions.entry(new).or_insert(*old);
+            }
+            (DirectoryEntry::Directory(new), Some(DirectoryEntry::Directory(old))) => {
+                pair_versions(store, new, *old, versions)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The order in which a [`log`] lists [`SnapShot`]s. Either way, no
+/// snapshot comes after one of its previous snapshots.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Order {
+    /// Keep each line of history together, following the first previous
+    /// snapshot first.
+    #[default]
+    Topological,
+    /// Newest first, by [`SnapShot::time`]. Snapshots without a time come
+    /// last.
+    Date,
+}
+
+/// Options controlling which [`SnapShot`]s a [`log`] includes.
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    /// The order to list snapshots in.
+    pub order: Order,
+    /// Only follow the first previous snapshot of each snapshot.
+    pub first_parent: bool,
+    /// The largest number of snapshots to include.
+    pub limit: Option<usize>,
+    /// Only include snapshots which changed something at one of these
+    /// paths, relative to the root of the tree.
+    pub paths: Vec<PathBuf>,
+}
+
+impl LogOptions {
+    fn previous<'a>(&self, snapshot: &'a SnapShot) -> &'a [ObjectId] {
+        if self.first_parent && !snapshot.previous.is_empty() {
+            &snapshot.previous[..1]
+        } else {
+            &snapshot.previous
+        }
+    }
+}
+
+/// The [`SnapShot`]s reachable from the given tips, in the [`Order`] given
+/// by the options.
+pub fn log<Store: ObjectStore>(
+    store: &Store,
+    tips: &[ObjectId],
+    options: &LogOptions,
+) -> Result<Vec<(ObjectId, SnapShot)>, Error<Store>> {
+    let mut snapshots: BTreeMap<ObjectId, SnapShot> = BTreeMap::new();
+    let mut stack: Vec<ObjectId> = tips.to_vec();
+    while let Some(id) = stack.pop() {
+        if let Entry::Vacant(entry) = snapshots.entry(id) {
+            let snapshot = SnapShot::read(store, id)?;
+            stack.extend(options.previous(&snapshot));
+            entry.insert(snapshot);
+        }
+    }
+    let mut children: BTreeMap<ObjectId, usize> = BTreeMap::new();
+    for snapshot in snapshots.values() {
+        for previous in options.previous(snapshot) {
+            *children.entry(*previous).or_default() += 1;
+        }
+    }
+
+    let mut log = Vec::new();
+    let mut ready: Vec<ObjectId> = tips
+        .iter()
+        .rev()
+        .filter(|id| !children.contains_key(id))
+        .copied()
+        .collect();
+    loop {
+        let next = match options.order {
+            Order::Topological => ready.pop(),
+            Order::Date => (0..ready.len())
+                .max_by_key(|i| {
+                    snapshots
+                        .get(&ready[*i])
+                        .and_then(SnapShot::time)
+                        .map(|time| time.seconds)
+                })
+                .map(|newest| ready.remove(newest)),
+        };
+        let Some(id) = next else {
+            break;
+        };
+        if options.limit.is_some_and(|limit| log.len() >= limit) {
+            break;
+        }
+        let Some(snapshot) = snapshots.remove(&id) else {
+            continue;
+        };
+        for previous in options.previous(&snapshot).iter().rev() {
+            let count = children.get_mut(previous).expect("counted every child");
+            *count -= 1;
+            if *count == 0 {
+                ready.push(*previous);
+            }
+        }
+        if touches(store, &snapshot, options)? {
+            log.push((id, snapshot));
+        }
+    }
+    Ok(log)
+}
+
+/// Whether the snapshot changed anything at one of the paths in the
+/// options compared to its previous snapshots. With no paths, every
+/// snapshot counts.
+fn touches<Store: ObjectStore>(
+    store: &Store,
+    snapshot: &SnapShot,
+    options: &LogOptions,
+) -> Result<bool, Error<Store>> {
+    if options.paths.is_empty() {
+        return Ok(true);
+    }
+    let entries = |id: ObjectId| -> Result<Vec<Option<DirectoryEntry>>, Error<Store>> {
+        let directory = Directory::read(store, id).map_err(Error::Directory)?;
+        options
+            .paths
+            .iter()
+            .map(|path| directory.entry(store, path).map_err(Error::Directory))
+            .collect()
+    };
+    let ours = entries(snapshot.directory)?;
+    let previous = options.previous(snapshot);
+    if previous.is_empty() {
+        return Ok(ours.iter().any(Option::is_some));
+    }
+    for previous in previous {
+        if entries(SnapShot::read(store, *previous)?.directory)? == ours {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Draws an ASCII graph of a [`log`], one [`SnapShot`] at a time, with a
+/// column for each line of history in progress.
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    columns: Vec<ObjectId>,
+}
+
+/// The lines a [`Graph`] draws for a single [`SnapShot`].
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct GraphLines {
+    /// Lines joining columns which all lead to this snapshot.
+    pub before: Vec<String>,
+    /// The line marking the snapshot itself, padded to the graph's width.
+    pub snapshot: String,
+    /// Lines opening or closing columns for its previous snapshots.
+    pub after: Vec<String>,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Draw the next snapshot of the log, given the previous snapshots it
+    /// is shown with.
+    pub fn next(&mut self, id: ObjectId, previous: &[ObjectId]) -> GraphLines {
+        let mut before = Vec::new();
+        let column = match self.columns.iter().position(|c| *c == id) {
+            Some(column) => column,
+            None => {
+                self.columns.push(id);
+                self.columns.len() - 1
+            }
+        };
+        if self.columns.iter().filter(|c| **c == id).count() > 1 {
+            let mut line = Row::new(self.columns.len());
+            let mut removed = 0;
+            for (j, c) in self.columns.iter().enumerate() {
+                if j > column && *c == id {
+                    removed += 1;
+                    line.set(2 * j - 1, '/');
+                } else if removed > 0 {
+                    line.set(2 * j - 1, '/');
+                } else {
+                    line.set(2 * j, '|');
+                }
+            }
+            before.push(line.finish());
+            let mut j = 0;
+            self.columns.retain(|c| {
+                j += 1;
+                j - 1 <= column || *c != id
+            });
+        }
+
+        let mut line = Row::new(self.columns.len());
+        for j in 0..self.columns.len() {
+            line.set(2 * j, if j == column { '*' } else { '|' });
+        }
+        let snapshot = line.chars.iter().collect();
+
+        let mut after = Vec::new();
+        let width = self.columns.len();
+        match previous.split_first() {
+            None => {
+                self.columns.remove(column);
+                if column < self.columns.len() {
+                    let mut line = Row::new(width);
+                    for j in 0..width {
+                        match j.cmp(&column) {
+                            Ordering::Less => line.set(2 * j, '|'),
+                            Ordering::Equal => {}
+                            Ordering::Greater => line.set(2 * j - 1, '/'),
+                        }
+                    }
+                    after.push(line.finish());
+                }
+            }
+            Some((first, rest)) => {
+                self.columns[column] = *first;
+                if !rest.is_empty() {
+                    let mut line = Row::new(width + 1);
+                    for j in 0..width {
+                        if j <= column {
+                            line.set(2 * j, '|');
+                        } else {
+                            line.set(2 * j + 1, '\\');
+                        }
+                    }
+                    line.set(2 * column + 1, '\\');
+                    after.push(line.finish());
+                    for (k, id) in rest.iter().enumerate() {
+                        self.columns.insert(column + 1 + k, *id);
+                    }
+                }
+            }
+        }
+        GraphLines {
+            before,
+            snapshot,
+            after,
+        }
+    }
+}
+
+struct Row {
+    chars: Vec<char>,
+}
+
+impl Row {
+    fn new(columns: usize) -> Self {
+        Row {
+            chars: vec![' '; 2 * columns],
+        }
+    }
+
+    fn set(&mut self, i: usize, c: char) {
+        self.chars[i] = c;
+    }
+
+    fn finish(self) -> String {
+        self.chars.iter().collect::<String>().trim_end().to_string()
+    }
+}
+
+#[test]
+fn test_merge_bases() {
+    use crate::{object_store::in_memory::InMemoryObjectStore, snapshot::test_snap};
+    let mut store = InMemoryObjectStore::new();
+    let mut snap = |message: &str, previous: Vec<ObjectId>| {
+        test_snap(&mut store, message, &[], previous, None)
+    };
+    let init = snap("init", vec![]);
+    let a = snap("a", vec![init]);
+    let b = snap("b", vec![a]);
+    let c = snap("c", vec![a]);
+    // A criss-cross: d and e both merge b and c.
+    let d = snap("d", vec![b, c]);
+    let e = snap("e", vec![c, b]);
+    let f = snap("f", vec![d]);
+    let g = snap("g", vec![e]);
+    let unrelated = snap("unrelated", vec![]);
+    assert_eq!(merge_bases(&store, &[b, c]).unwrap(), BTreeSet::from([a]));
+    assert_eq!(
+        merge_bases(&store, &[f, g]).unwrap(),
+        BTreeSet::from([b, c])
+    );
+    assert_eq!(
+        merge_bases(&store, &[f, g, c]).unwrap(),
+        BTreeSet::from([c])
+    );
+    assert_eq!(merge_bases(&store, &[f, b]).unwrap(), BTreeSet::from([b]));
+    assert_eq!(
+        merge_bases(&store, &[f, unrelated]).unwrap(),
+        BTreeSet::new()
+    );
+    assert!(is_ancestor(&store, a, f).unwrap());
+    assert!(is_ancestor(&store, f, f).unwrap());
+    assert!(!is_ancestor(&store, f, g).unwrap());
+    assert!(!is_ancestor(&store, unrelated, g).unwrap());
+}
+
+#[test]
+fn test_log() {
+    use crate::{object_store::in_memory::InMemoryObjectStore, snapshot::test_snap};
+    let mut store = InMemoryObjectStore::new();
+    let mut snap = |message: &str, files: Vec<&str>, previous: Vec<ObjectId>| {
+        let files: Vec<(&str, &[u8])> = files.into_iter().map(|f| (f, f.as_bytes())).collect();
+        test_snap(&mut store, message, &files, previous, None)
+    };
+    let init = snap("init", vec![], vec![]);
+    let a = snap("a", vec!["a"], vec![init]);
+    let b = snap("b", vec!["a", "b"], vec![a]);
+    let c = snap("c", vec!["a", "c"], vec![a]);
+    let d = snap("d", vec!["a", "b", "c"], vec![b, c]);
+    let ids = |options: &LogOptions| -> Vec<ObjectId> {
+        log(&store, &[d], options)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    };
+    assert_eq!(ids(&LogOptions::default()), vec![d, b, c, a, init]);
+    assert_eq!(
+        ids(&LogOptions {
+            first_parent: true,
+            ..LogOptions::default()
+        }),
+        vec![d, b, a, init]
+    );
+    assert_eq!(
+        ids(&LogOptions {
+            limit: Some(2),
+            ..LogOptions::default()
+        }),
+        vec![d, b]
+    );
+    assert_eq!(
+        ids(&LogOptions {
+            paths: vec![PathBuf::from("c")],
+            ..LogOptions::default()
+        }),
+        vec![c]
+    );
+
+    let mut graph = Graph::new();
+    let lines: Vec<String> = log(&store, &[d], &LogOptions::default())
+        .unwrap()
+        .into_iter()
+        .flat_map(|(id, snapshot)| {
+            let lines = graph.next(id, &snapshot.previous);
+            let mut out = lines.before;
+            out.push(format!("{}{}", lines.snapshot, snapshot.message));
+            out.extend(lines.after);
+            out
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        vec!["* d", "|\\", "* | b", "| * c", "|/", "* a", "* init"]
+    );
+}
+
+#[test]
+fn test_log_date_order() {
+    use crate::{
+        object_store::in_memory::InMemoryObjectStore,
+        snapshot::{test_snap, Timestamp},
+    };
+    let mut store = InMemoryObjectStore::new();
+    let mut snap = |message: &str, seconds: i64, previous: Vec<ObjectId>| {
+        let time = Timestamp { seconds, offset: 0 };
+        test_snap(&mut store, message, &[], previous, Some(time))
+    };
+    let a = snap("a", 0, vec![]);
+    let b = snap("b", 1, vec![a]);
+    let c = snap("c", 3, vec![a]);
+    let d = snap("d", 2, vec![b]);
+    let e = snap("e", 4, vec![d, c]);
+    let ids = |order: Order| -> Vec<ObjectId> {
+        let options = LogOptions {
+            order,
+            ..LogOptions::default()
+        };
+        log(&store, &[e], &options)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    };
+    assert_eq!(ids(Order::Topological), vec![e, d, b, c, a]);
+    assert_eq!(ids(Order::Date), vec![e, c, d, b, a]);
+}
@@ -0,0 +1,346 @@
+use std::{
+    fmt,
+    fs::read_to_string,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The name of the files whose patterns apply to the directory they are in
+/// and everything below it.
+pub const IGNORE_FILE: &str = ".revignore";
+
+/// A gitignore-style pattern matching paths relative to the directory it
+/// applies to.
+///
+/// - A pattern without a `/`, other than at the end, matches a name at any
+///   level. Otherwise it is anchored, and matches the whole path from the
+///   directory, with any leading `/` only serving to anchor it.
+/// - A trailing `/` makes the pattern only match directories.
+/// - `*` matches anything but a `/`, `?` any single character but a `/`,
+///   `[a-z]` and `[!a-z]` a character in or not in a class, and `\`
+///   escapes the next character.
+/// - `**/` at the start matches in every directory, `/**/` matches any
+///   number of directories, and `/**` at the end matches everything inside.
+/// - A leading `!` negates the pattern, bringing back a path which an
+///   earlier pattern matched.
+/// - Empty patterns and those starting with `#` match nothing.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct Pattern {
+    text: String,
+    negated: bool,
+    directory_only: bool,
+    /// The pattern split at each `/`, starting with `**` if it is not
+    /// anchored.
+    segments: Vec<Vec<char>>,
+}
+
+impl Pattern {
+    pub fn new(text: &str) -> Self {
+        let mut pattern = Pattern {
+            text: String::from(text),
+            negated: false,
+            directory_only: false,
+            segments: Vec::new(),
+        };
+        if text.is_empty() || text.starts_with('#') {
+            return pattern;
+        }
+        let mut rest = text;
+        if let Some(negated) = rest.strip_prefix('!') {
+            pattern.negated = true;
+            rest = negated;
+        }
+        if let Some(directory) = rest.strip_suffix('/') {
+            pattern.directory_only = true;
+            rest = directory;
+        }
+        if !rest.contains('/') {
+            pattern.segments.push(vec!['*', '*']);
+        }
+        pattern.segments.extend(
+            rest.split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| segment.chars().collect()),
+        );
+        pattern
+    }
+
+    /// Whether this pattern brings back paths rather than ignoring them.
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    /// Whether the pattern matches the path, given as its names relative to
+    /// the directory the pattern applies to.
+    pub fn matches(&self, path: &[&str], is_dir: bool) -> bool {
+        if self.segments.is_empty() || (self.directory_only && !is_dir) {
+            return false;
+        }
+        let path: Vec<Vec<char>> = path.iter().map(|name| name.chars().collect()).collect();
+        match_segments(&self.segments, &path)
+    }
+}
+
+impl From<String> for Pattern {
+    fn from(text: String) -> Self {
+        Pattern::new(&text)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.text
+    }
+}
+
+/// The patterns from one source, such as a [`IGNORE_FILE`], applying to
+/// paths under one directory.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Rules {
+    /// Where the patterns came from, to explain what matched.
+    pub source: PathBuf,
+    /// The directory, relative to the work tree, which the patterns are
+    /// relative to.
+    pub directory: PathBuf,
+    /// The patterns with the line each is on, later ones taking precedence.
+    pub patterns: Vec<(usize, Pattern)>,
+}
+
+impl Rules {
+    /// Parse one pattern per line.
+    pub fn parse(source: PathBuf, directory: PathBuf, text: &str) -> Self {
+        let patterns = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, Pattern::new(line.trim_end())))
+            .filter(|(_, pattern)| !pattern.segments.is_empty())
+            .collect();
+        Rules {
+            source,
+            directory,
+            patterns,
+        }
+    }
+
+    /// Read the [`IGNORE_FILE`] in `dir`, which is at `directory` relative
+    /// to the work tree, if there is one.
+    pub fn read(dir: &Path, directory: &Path) -> std::io::Result<Option<Self>> {
+        let source = directory.join(IGNORE_FILE);
+        match read_to_string(dir.join(IGNORE_FILE)) {
+            Ok(text) => Ok(Some(Rules::parse(source, directory.into(), &text))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The last pattern matching the path, relative to the work tree.
+    fn last_match(&self, names: &[&str], is_dir: bool) -> Option<Match<'_>> {
+        let depth = self.directory.components().count();
+        if names.len() <= depth
+            || !self
+                .directory
+                .components()
+                .zip(names)
+                .all(|(component, name)| component.as_os_str() == *name)
+        {
+            return None;
+        }
+        self.patterns
+            .iter()
+            .rev()
+            .find(|(_, pattern)| pattern.matches(&names[depth..], is_dir))
+            .map(|(line, pattern)| Match {
+                source: &self.source,
+                line: *line,
+                pattern,
+            })
+    }
+}
+
+/// The pattern which decided whether a path is ignored.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Match<'a> {
+    pub source: &'a Path,
+    pub line: usize,
+    pub pattern: &'a Pattern,
+}
+
+impl fmt::Display for Match<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.source.display(),
+            self.line,
+            self.pattern.text
+        )
+    }
+}
+
+/// [`Rules`] layered on top of each other, such as those of `.rev/ignores`
+/// and then of the [`IGNORE_FILE`]s in the directories leading to a path,
+/// with later layers taking precedence.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Layers {
+    rules: Vec<Rules>,
+}
+
+impl Layers {
+    pub fn new(rules: Rules) -> Self {
+        Layers { rules: vec![rules] }
+    }
+
+    pub fn push(&mut self, rules: Rules) {
+        self.rules.push(rules);
+    }
+
+    pub fn pop(&mut self) -> Option<Rules> {
+        self.rules.pop()
+    }
+
+    /// Push the [`IGNORE_FILE`]s of the work tree and of every directory
+    /// leading down to `directory`, relative to it.
+    pub fn read_down_to(&mut self, work_tree: &Path, directory: &Path) -> std::io::Result<()> {
+        let mut current = PathBuf::new();
+        self.rules.extend(Rules::read(work_tree, &current)?);
+        for component in directory.components() {
+            current.push(component);
+            self.rules
+                .extend(Rules::read(&work_tree.join(&current), &current)?);
+        }
+        Ok(())
+    }
+
+    /// Whether the path, relative to the work tree, is ignored, either
+    /// itself or because a directory it is in is.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        self.explain(path, is_dir)
+            .is_some_and(|found| !found.pattern.is_negated())
+    }
+
+    /// The pattern which decides whether the path is ignored, being the
+    /// one which ignores a directory it is in if there is one.
+    pub fn explain(&self, path: &Path, is_dir: bool) -> Option<Match<'_>> {
+        let names: Vec<String> = path
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        (1..names.len())
+            .filter_map(|end| self.last_match(&names[..end], true))
+            .find(|found| !found.pattern.is_negated())
+            .or_else(|| self.last_match(&names, is_dir))
+    }
+
+    fn last_match(&self, names: &[&str], is_dir: bool) -> Option<Match<'_>> {
+        self.rules
+            .iter()
+            .rev()
+            .find_map(|rules| rules.last_match(names, is_dir))
+    }
+}
+
+fn match_segments(pattern: &[Vec<char>], path: &[Vec<char>]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == &['*', '*'] => {
+            if rest.is_empty() {
+                // A trailing `**` matches what is inside, not the directory.
+                return !path.is_empty();
+            }
+            (0..=path.len()).any(|skip| match_segments(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path)) => match_name(first, name) && match_segments(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// Whether a single name matches a pattern without any `/`.
+fn match_name(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| match_name(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && match_name(&pattern[1..], &name[1..]),
+        Some('[') => match (
+            name.first(),
+            match_class(&pattern[1..], name.first().copied()),
+        ) {
+            (Some(_), Some((true, length))) => match_name(&pattern[1 + length..], &name[1..]),
+            (_, Some((false, _))) | (None, Some(_)) => false,
+            // Without a closing `]` this is an ordinary character.
+            (_, None) => name.first() == Some(&'[') && match_name(&pattern[1..], &name[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && match_name(&pattern[2..], &name[1..])
+        }
+        Some(c) => name.first() == Some(c) && match_name(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Whether the character is in the class at the start of the pattern,
+/// just after its `[`, along with the length of the rest of the class
+/// including its `]`. `None` if the class is never closed.
+fn match_class(pattern: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = *pattern.get(i)?;
+        if start == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|end| *end != ']') {
+            let end = pattern[i + 2];
+            matched |= c.is_some_and(|c| start <= c && c <= end);
+            i += 3;
+        } else {
+            matched |= c == Some(start);
+            i += 1;
+        }
+    }
+}
+
+#[test]
+fn test_pattern() {
+    let matches = |pattern: &str, path: &str, is_dir: bool| {
+        let path: Vec<&str> = path.split('/').collect();
+        Pattern::new(pattern).matches(&path, is_dir)
+    };
+    assert!(matches("*.o", "main.o", false));
+    assert!(matches("*.o", "src/deep/main.o", false));
+    assert!(!matches("*.o", "main.c", false));
+    assert!(!matches("*.o", "main.o/child", false));
+    assert!(matches("/build", "build", true));
+    assert!(!matches("/build", "src/build", true));
+    assert!(matches("build/", "src/build", true));
+    assert!(!matches("build/", "src/build", false));
+    assert!(matches("doc/*.txt", "doc/notes.txt", false));
+    assert!(!matches("doc/*.txt", "doc/more/notes.txt", false));
+    assert!(!matches("doc/*.txt", "src/doc/notes.txt", false));
+    assert!(matches("logs/**/*.tmp", "logs/a.tmp", false));
+    assert!(matches("logs/**/*.tmp", "logs/a/b/c.tmp", false));
+    assert!(matches("**/logs", "a/logs", true));
+    assert!(matches("logs/**", "logs/a", false));
+    assert!(!matches("logs/**", "logs", true));
+    assert!(matches("file.[ch]", "file.h", false));
+    assert!(matches("file.[!ch]", "file.o", false));
+    assert!(!matches("file.[!ch]", "file.c", false));
+    assert!(matches("[a-c]?", "bz", false));
+    assert!(matches("\\*star", "*star", false));
+    assert!(!matches("\\*star", "no star", false));
+    assert!(matches("[unclosed", "[unclosed", false));
+    assert!(!matches("# comment", "# comment", false));
+    let keep = Pattern::new("!keep.me");
+    assert!(keep.is_negated());
+    assert!(keep.matches(&["keep.me"], false));
+}
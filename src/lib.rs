@@ -1,16 +1,34 @@
-#![feature(fs_try_exists)]
-
 //! # Revision Control
 //!
 //! This is an implementation of a basic revision control system.
 
+mod atomic;
 mod hex;
 
+/// Content-defined chunking, which splits large files into pieces shared
+/// between versions.
+pub mod chunking;
+/// Layered configuration from the repository, the user and the
+/// environment.
+pub mod config;
+/// Binary deltas which rebuild one version of an object from another.
+pub mod delta;
 /// A data structure representing a directory structure with
 /// names of files and their `ObjectId`.
 pub mod directory;
 /// A .rev directory as well as associated convenience functions.
 pub mod dot_rev;
+/// Checking a store for missing, corrupt and unreachable objects.
+pub mod fsck;
+/// Garbage collection of objects which are no longer reachable.
+pub mod gc;
+/// Traversal of the [`SnapShot`](snapshot::SnapShot) graph, such as
+/// finding merge bases.
+pub mod history;
+/// Gitignore-style patterns for paths left out of snapshots.
+pub mod ignore;
+/// Line-level three-way merging of file contents.
+pub mod merge;
 /// Hash-based binary object identifier type called `ObjectId`.
 pub mod object_id;
 /// Content addressible store API using `ObjectId` as the address.
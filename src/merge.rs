@@ -0,0 +1,228 @@
+/// The result of merging the contents of a file changed on both sides.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum FileMerge {
+    /// The changes did not overlap and were combined.
+    Clean(Vec<u8>),
+    /// Some changes overlapped and were written out between conflict
+    /// markers.
+    Conflict(Vec<u8>),
+    /// One of the versions is binary, so no line merge was attempted.
+    Binary,
+    /// One of the versions differs from the base by more than
+    /// [`MAX_EDITS`] lines, so the line merge was given up on.
+    TooLarge,
+}
+
+/// The most lines a side may have changed from the base for a line merge
+/// to be attempted, since matching up the lines takes memory quadratic in
+/// the number of changes.
+pub const MAX_EDITS: usize = 2048;
+
+/// Heuristic used to decide whether contents are binary: a NUL byte
+/// anywhere in the first few kilobytes.
+pub fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(8000).any(|b| *b == 0)
+}
+
+/// Merge the changes made from `base` to `ours` with the changes made from
+/// `base` to `theirs`, line by line, in the style of diff3.
+///
+/// Changes which touch different lines of `base` are combined. Where the
+/// two sides changed the same lines differently, both versions are written
+/// out between `<<<<<<<`, `=======` and `>>>>>>>` markers labelled with
+/// `ours_label` and `theirs_label`.
+pub fn merge_lines(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    ours_label: &str,
+    theirs_label: &str,
+) -> FileMerge {
+    if is_binary(base) || is_binary(ours) || is_binary(theirs) {
+        return FileMerge::Binary;
+    }
+    let base: Vec<&[u8]> = lines(base);
+    let ours: Vec<&[u8]> = lines(ours);
+    let theirs: Vec<&[u8]> = lines(theirs);
+    let Some(ours_matches) = matches(&base, &ours) else {
+        return FileMerge::TooLarge;
+    };
+    let Some(theirs_matches) = matches(&base, &theirs) else {
+        return FileMerge::TooLarge;
+    };
+
+    let mut out = Vec::new();
+    let mut conflicted = false;
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        let stable = (b..base.len()).find_map(|i| match (ours_matches[i], theirs_matches[i]) {
+            (Some(j), Some(k)) => Some((i, j, k)),
+            _ => None,
+        });
+        let (b_end, o_end, t_end) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+        if (b_end, o_end, t_end) == (b, o, t) {
+            if stable.is_none() {
+                break;
+            }
+            out.extend_from_slice(base[b]);
+            b += 1;
+            o += 1;
+            t += 1;
+            continue;
+        }
+        let base_chunk = &base[b..b_end];
+        let ours_chunk = &ours[o..o_end];
+        let theirs_chunk = &theirs[t..t_end];
+        if ours_chunk == base_chunk || ours_chunk == theirs_chunk {
+            extend_lines(&mut out, theirs_chunk);
+        } else if theirs_chunk == base_chunk {
+            extend_lines(&mut out, ours_chunk);
+        } else {
+            conflicted = true;
+            out.extend_from_slice(format!("<<<<<<< {}\n", ours_label).as_bytes());
+            extend_lines(&mut out, ours_chunk);
+            end_line(&mut out);
+            out.extend_from_slice(b"=======\n");
+            extend_lines(&mut out, theirs_chunk);
+            end_line(&mut out);
+            out.extend_from_slice(format!(">>>>>>> {}\n", theirs_label).as_bytes());
+        }
+        (b, o, t) = (b_end, o_end, t_end);
+    }
+    if conflicted {
+        FileMerge::Conflict(out)
+    } else {
+        FileMerge::Clean(out)
+    }
+}
+
+fn lines(contents: &[u8]) -> Vec<&[u8]> {
+    contents.split_inclusive(|b| *b == b'\n').collect()
+}
+
+fn extend_lines(out: &mut Vec<u8>, lines: &[&[u8]]) {
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+}
+
+fn end_line(out: &mut Vec<u8>) {
+    if !out.is_empty() && !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+/// For each line of `a`, the line of `b` it is matched with in a shortest
+/// edit script from `a` to `b`, computed with Myers' algorithm, or `None`
+/// if that script is longer than [`MAX_EDITS`].
+fn matches(a: &[&[u8]], b: &[&[u8]]) -> Option<Vec<Option<usize>>> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let snake = |mut x: isize, mut y: isize| {
+        while x < n && y < m && a[x as usize] == b[y as usize] {
+            x += 1;
+            y += 1;
+        }
+        x
+    };
+
+    // `v[k + offset]` is the furthest x reached on diagonal k = x - y.
+    // Before each round d we keep the part of `v` that round can read.
+    let offset = n + m + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: for d in 0..=(n + m) {
+        if d > MAX_EDITS as isize {
+            return None;
+        }
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            v[i] = snake(x, x - k);
+            if v[i] >= n && v[i] - k >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut matched = vec![None; a.len()];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let previous_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = at(previous_k);
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            matched[x as usize] = Some(y as usize);
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+    Some(matched)
+}
+
+#[test]
+fn test_merge_lines_clean() {
+    let base = b"a\nb\nc\nd\ne\n";
+    let ours = b"a\nB\nc\nd\ne\n";
+    let theirs = b"a\nb\nc\nD\ne\nf\n";
+    assert_eq!(
+        merge_lines(base, ours, theirs, "ours", "theirs"),
+        FileMerge::Clean(b"a\nB\nc\nD\ne\nf\n".to_vec())
+    );
+    assert_eq!(
+        merge_lines(base, ours, ours, "ours", "theirs"),
+        FileMerge::Clean(ours.to_vec())
+    );
+    assert_eq!(
+        merge_lines(b"", b"", b"a\n", "ours", "theirs"),
+        FileMerge::Clean(b"a\n".to_vec())
+    );
+}
+
+#[test]
+fn test_merge_lines_conflict() {
+    let base = b"a\nb\nc\n";
+    let ours = b"a\nB\nc\n";
+    let theirs = b"a\nbb\nc\n";
+    assert_eq!(
+        merge_lines(base, ours, theirs, "dev", "feature"),
+        FileMerge::Conflict(b"a\n<<<<<<< dev\nB\n=======\nbb\n>>>>>>> feature\nc\n".to_vec())
+    );
+    assert_eq!(
+        merge_lines(b"a\n", b"b", b"c", "dev", "feature"),
+        FileMerge::Conflict(b"<<<<<<< dev\nb\n=======\nc\n>>>>>>> feature\n".to_vec())
+    );
+    assert_eq!(
+        merge_lines(base, b"a\0\n", theirs, "dev", "feature"),
+        FileMerge::Binary
+    );
+    let numbered = |prefix: &str| -> Vec<u8> {
+        (0..MAX_EDITS)
+            .flat_map(|i| format!("{}{}\n", prefix, i).into_bytes())
+            .collect()
+    };
+    assert_eq!(
+        merge_lines(
+            &numbered(""),
+            &numbered("o"),
+            &numbered("t"),
+            "dev",
+            "feature"
+        ),
+        FileMerge::TooLarge
+    );
+}
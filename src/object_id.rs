@@ -7,6 +7,7 @@ use std::{
     fs::File,
     io::Read,
     path::Path,
+    str::FromStr,
 };
 
 /// An identifier for a particular piece of binary content.
@@ -34,13 +35,24 @@ impl<'de> Deserialize<'de> for ObjectId {
         let b: hex::Hex = Deserialize::deserialize(deserializer)?;
         let v: Vec<u8> = b.into();
         let mut bytes: [u8; 32] = [0; 32];
-        for i in 0..32 {
-            bytes[i] = v[i];
-        }
+        bytes.copy_from_slice(&v[0..32]);
         Ok(ObjectId(Hash::from(bytes)))
     }
 }
 
+impl ObjectId {
+    /// The raw bytes of the hash.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+
+    /// The [`ObjectId`] with the given raw hash bytes. Unlike the `From`
+    /// implementations, this does not hash `bytes`.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        ObjectId(Hash::from(bytes))
+    }
+}
+
 impl Ord for ObjectId {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.0.as_bytes().cmp(other.0.as_bytes())
@@ -49,7 +61,7 @@ impl Ord for ObjectId {
 
 impl PartialOrd for ObjectId {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0.as_bytes().partial_cmp(other.0.as_bytes())
+        Some(self.cmp(other))
     }
 }
 
@@ -66,15 +78,44 @@ impl Debug for ObjectId {
     }
 }
 
+/// The error returned when a string is not the hexadecimal form of an
+/// [`ObjectId`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseObjectIdError(pub String);
+
+impl Display for ParseObjectIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not an object id: {}", self.0)
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = ParseObjectIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64
+            || !s
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        {
+            return Err(ParseObjectIdError(String::from(s)));
+        }
+        let v: Vec<u8> = hex::Hex(s.as_bytes().to_vec()).into();
+        let mut bytes: [u8; 32] = [0; 32];
+        bytes.copy_from_slice(&v);
+        Ok(ObjectId(Hash::from(bytes)))
+    }
+}
+
 impl From<&Vec<u8>> for ObjectId {
     fn from(vec: &Vec<u8>) -> Self {
-        ObjectId(blake3::hash(&vec))
+        ObjectId(blake3::hash(vec))
     }
 }
 
 impl From<&[u8]> for ObjectId {
     fn from(bytes: &[u8]) -> Self {
-        ObjectId(blake3::hash(&bytes))
+        ObjectId(blake3::hash(bytes))
     }
 }
 
@@ -88,7 +129,7 @@ impl TryFrom<File> for ObjectId {
     }
 }
 
-impl<'a> TryFrom<&Path> for ObjectId {
+impl TryFrom<&Path> for ObjectId {
     type Error = std::io::Error;
 
     fn try_from(p: &Path) -> Result<Self, Self::Error> {
@@ -104,3 +145,15 @@ fn test_try_from() -> Result<(), std::io::Error> {
     assert_eq!(object_id, object_id_prime);
     Ok(())
 }
+
+#[test]
+fn test_from_str() {
+    let object_id = ObjectId::from(b"hello, world".as_slice());
+    assert_eq!(object_id.to_string().parse(), Ok(object_id));
+    assert!("hello".parse::<ObjectId>().is_err());
+    assert!(object_id
+        .to_string()
+        .to_uppercase()
+        .parse::<ObjectId>()
+        .is_err());
+}
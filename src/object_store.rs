@@ -1,11 +1,29 @@
+use std::{
+    io::{Cursor, Read},
+    time::SystemTime,
+};
+
 use crate::object_id::ObjectId;
 
+/// The encoding of objects on disk, with optional compression.
+pub mod codec;
+
 /// A persistent implementation using a directory.
 pub mod directory;
 
 /// An ephemeral implementation using a [`BTreeMap`].
 pub mod in_memory;
 
+/// An implementation which reads from one store before falling back to
+/// another.
+pub mod layered;
+
+/// A persistent implementation packing many objects into each file.
+pub mod pack;
+
+/// Checking that objects hash to their [`ObjectId`].
+pub mod verify;
+
 /// A trait for maps which store binary objects based on their
 /// [`ObjectId`].
 pub trait ObjectStore {
@@ -19,4 +37,109 @@ pub trait ObjectStore {
 
     /// Insert the [`ObjectId`] into the store.
     fn insert(&mut self, object: &[u8]) -> Result<ObjectId, Self::Error>;
+
+    /// The [`ObjectId`]s of every object in the store, in no particular
+    /// order.
+    fn ids(&self) -> Ids<'_, Self::Error>;
+
+    /// Remove the object from the store, if it is present.
+    fn remove(&mut self, id: ObjectId) -> Result<(), Self::Error>;
+
+    /// When the object was last written, or `None` if the store does not
+    /// keep track, in which case it is treated as arbitrarily old.
+    fn modified(&self, _id: ObjectId) -> Result<Option<SystemTime>, Self::Error> {
+        Ok(None)
+    }
+
+    /// The size of the object with the [`ObjectId`], if it is present.
+    ///
+    /// By default this reads the whole object, but stores on disk look at
+    /// their files for objects which are not compressed.
+    fn size_of(&self, id: ObjectId) -> Result<Option<u64>, Self::Error> {
+        Ok(self.read(id)?.map(|object| object.len() as u64))
+    }
+
+    /// Insert each of the objects, returning their [`ObjectId`]s in the
+    /// same order.
+    fn insert_many(&mut self, objects: &[&[u8]]) -> Result<Vec<ObjectId>, Self::Error> {
+        objects.iter().map(|object| self.insert(object)).collect()
+    }
+
+    /// Read each of the [`ObjectId`]s out of the store, in the same order.
+    fn read_many(&self, ids: &[ObjectId]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        ids.iter().map(|id| self.read(*id)).collect()
+    }
+
+    /// Insert the object read from `reader`, returning its [`ObjectId`].
+    ///
+    /// By default the object is read into memory first, but stores on
+    /// disk copy large objects across in pieces, hashing them on the way.
+    fn insert_stream(
+        &mut self,
+        reader: &mut dyn Read,
+    ) -> Result<ObjectId, StreamError<Self::Error>> {
+        let mut object = Vec::new();
+        reader.read_to_end(&mut object).map_err(StreamError::Read)?;
+        self.insert(&object).map_err(StreamError::Store)
+    }
+
+    /// A reader for the object with the [`ObjectId`], if it is present.
+    ///
+    /// By default this reads the whole object into memory, but stores on
+    /// disk read uncompressed objects straight from their files.
+    fn read_stream(&self, id: ObjectId) -> Result<Option<Box<dyn Read + '_>>, Self::Error> {
+        Ok(self
+            .read(id)?
+            .map(|object| Box::new(Cursor::new(object)) as Box<dyn Read>))
+    }
+}
+
+/// An iterator over the [`ObjectId`]s in a store, which can fail part of
+/// the way through.
+pub type Ids<'a, E> = Box<dyn Iterator<Item = Result<ObjectId, E>> + 'a>;
+
+/// An error from [`ObjectStore::insert_stream`], which can fail to read
+/// the object as well as to store it.
+#[derive(Debug)]
+pub enum StreamError<E> {
+    Read(std::io::Error),
+    Store(E),
+}
+
+/// Exercise every method of an empty [`ObjectStore`], so that each
+/// implementation is tested against the same expectations.
+#[cfg(test)]
+pub(crate) fn test_object_store<Store: ObjectStore>(store: &mut Store)
+where
+    Store::Error: std::fmt::Debug,
+{
+    use std::collections::BTreeSet;
+
+    let objects: [&[u8]; 3] = [b"hello, world", b"goodbye, world", b""];
+    let ids = store.insert_many(&objects).unwrap();
+    assert_eq!(ids.len(), 3);
+    for (id, object) in ids.iter().zip(objects) {
+        assert_eq!(*id, ObjectId::from(object));
+        assert!(store.has(*id).unwrap());
+        assert_eq!(store.size_of(*id).unwrap(), Some(object.len() as u64));
+    }
+    let missing = ObjectId::from(&b"missing"[..]);
+    assert_eq!(
+        store.read_many(&[ids[1], missing, ids[0]]).unwrap(),
+        vec![Some(objects[1].to_vec()), None, Some(objects[0].to_vec())]
+    );
+    assert_eq!(store.size_of(missing).unwrap(), None);
+
+    let stored: BTreeSet<ObjectId> = store.ids().map(Result::unwrap).collect();
+    assert_eq!(stored, ids.iter().copied().collect());
+    assert_eq!(store.ids().count(), 3);
+
+    store.remove(ids[0]).unwrap();
+    store.remove(missing).unwrap();
+    assert!(!store.has(ids[0]).unwrap());
+    assert_eq!(store.read(ids[0]).unwrap(), None);
+    let stored: BTreeSet<ObjectId> = store.ids().map(Result::unwrap).collect();
+    assert_eq!(stored, BTreeSet::from([ids[1], ids[2]]));
+    assert_eq!(store.insert(objects[0]).unwrap(), ids[0]);
+    assert_eq!(store.read(ids[0]).unwrap(), Some(objects[0].to_vec()));
 }
@@ -0,0 +1,119 @@
+use std::{fmt, io::ErrorKind, str::FromStr};
+
+/// Marks an object written with a header. Objects written before headers
+/// were introduced are the raw contents, which are very unlikely to start
+/// with a NUL byte followed by `rev`.
+const MAGIC: &[u8; 4] = b"\0rev";
+
+/// How the contents of an object are encoded on disk, after a header
+/// naming the codec. The [`ObjectId`](crate::object_id::ObjectId) is
+/// always that of the decoded contents.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Codec {
+    /// The contents as they are.
+    None,
+    /// [zstd](https://facebook.github.io/zstd/) compression, which works
+    /// well on the pretty JSON of directories and snapshots as well as on
+    /// most source files.
+    #[default]
+    Zstd,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::None => write!(f, "none"),
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(format!("unknown codec {}", s)),
+        }
+    }
+}
+
+/// The header and encoded contents to write for an object, falling back
+/// to [`Codec::None`] when compression would not make it smaller.
+pub fn encode(codec: Codec, object: &[u8]) -> Vec<u8> {
+    let payload = match codec {
+        Codec::None => None,
+        Codec::Zstd => Some(compress(object)).filter(|compressed| compressed.len() < object.len()),
+    };
+    let (codec, payload) = match &payload {
+        Some(compressed) => (codec, &compressed[..]),
+        None => (Codec::None, object),
+    };
+    [&header(codec)[..], payload].concat()
+}
+
+/// The header written before contents encoded with the codec.
+pub fn header(codec: Codec) -> [u8; 5] {
+    let mut header = [0; 5];
+    header[..4].copy_from_slice(MAGIC);
+    header[4] = codec.tag();
+    header
+}
+
+/// The contents of an object as read from disk, along with the codec it
+/// was written with, which is `None` for objects without a header.
+pub fn decode(bytes: Vec<u8>) -> Result<(Codec, Vec<u8>), std::io::Error> {
+    let Some(rest) = bytes.strip_prefix(&MAGIC[..]) else {
+        return Ok((Codec::None, bytes));
+    };
+    let invalid = |message: &str| std::io::Error::new(ErrorKind::InvalidData, message);
+    match rest.split_first() {
+        Some((0, object)) => Ok((Codec::None, object.to_vec())),
+        Some((1, compressed)) => decompress(compressed)
+            .map(|object| (Codec::Zstd, object))
+            .ok_or_else(|| invalid("corrupt compressed object")),
+        _ => Err(invalid("object written with an unknown codec")),
+    }
+}
+
+fn compress(object: &[u8]) -> Vec<u8> {
+    zstd::stream::encode_all(object, 0).expect("compressing in memory")
+}
+
+/// Streams the contents out rather than trusting the size a corrupt frame
+/// might claim, so nothing large is allocated up front.
+fn decompress(compressed: &[u8]) -> Option<Vec<u8>> {
+    zstd::stream::decode_all(compressed).ok()
+}
+
+#[test]
+fn test_codec() {
+    let entries: Vec<_> = (0..100)
+        .map(|i| serde_json::json!({ "name": format!("file_{}.rs", i), "kind": "file" }))
+        .collect();
+    let json = serde_json::to_vec_pretty(&entries).unwrap();
+    let encoded = encode(Codec::Zstd, &json);
+    assert!(encoded.len() < json.len() / 2);
+    assert_eq!(decode(encoded).unwrap(), (Codec::Zstd, json.clone()));
+    assert_eq!(decode(json.clone()).unwrap(), (Codec::None, json.clone()));
+    assert_eq!(
+        decode(encode(Codec::None, &json)).unwrap(),
+        (Codec::None, json)
+    );
+    for object in [&b""[..], b"abc", b"aaaaaaaaaaaaaaaaaaaaaaaaa", b"\0rev"] {
+        let (_, decoded) = decode(encode(Codec::Zstd, object)).unwrap();
+        assert_eq!(decoded, object);
+    }
+    assert!(decode(b"\0rev\x07".to_vec()).is_err());
+}
@@ -1,30 +1,86 @@
 use std::{
-    fs::{create_dir, try_exists, File},
-    io::{ErrorKind, Read, Write},
-    path::PathBuf,
+    fs::{create_dir, exists, File},
+    io::{Cursor, ErrorKind, Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use crate::object_id::ObjectId;
+use crate::{
+    atomic::{self, AtomicFile},
+    object_id::ObjectId,
+};
+
+use super::{
+    codec::{self, Codec},
+    verify::{verify, VerifyingReader},
+    Ids, ObjectStore, StreamError,
+};
 
-use super::ObjectStore;
+/// Objects streamed in are held in memory up to this size, beyond which
+/// they are copied to disk uncompressed as they are read.
+const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
 
 /// A persistent [`ObjectStore`] stored in a directory,
 /// using the first two hexadecimal characters of the [`ObjectId`]
 /// to determine which directory to place the binary object in
 /// and creating a file with the rest of the hexadecimal characters
 /// as the file name.
+///
+/// Objects are written to a temporary file which is renamed into place
+/// once it is complete, so a crash never leaves a partial object behind.
+/// Each file holds the object encoded with the store's [`Codec`].
 #[derive(Debug, Clone)]
 pub struct DirectoryObjectStore {
     root: PathBuf,
+    codec: Codec,
+    verify: bool,
 }
 
 impl DirectoryObjectStore {
     pub fn new(root: PathBuf) -> Result<Self, std::io::Error> {
-        if !try_exists(&root)? {
+        if !exists(&root)? {
             log::info!("creating directory store root: {:?}", root);
             create_dir(&root)?;
         }
-        Ok(Self { root })
+        Ok(Self {
+            root,
+            codec: Codec::default(),
+            verify: false,
+        })
+    }
+
+    /// Check that every object read hashes to its [`ObjectId`], failing
+    /// with [`ErrorKind::InvalidData`] if it does not.
+    pub fn with_verify(self, verify: bool) -> Self {
+        Self { verify, ..self }
+    }
+
+    /// Use the given [`Codec`] for objects inserted from now on. Objects
+    /// written with any codec can always be read.
+    pub fn with_codec(self, codec: Codec) -> Self {
+        Self { codec, ..self }
+    }
+
+    /// Rewrite the object with this store's [`Codec`], returning whether
+    /// that changed it.
+    pub fn recompress(&mut self, id: ObjectId) -> Result<bool, std::io::Error> {
+        let s: String = format!("{}", id);
+        let path = self.root.join(&s[0..2]).join(&s[2..]);
+        let bytes = std::fs::read(&path)?;
+        let (_, object) = codec::decode(bytes.clone())?;
+        let encoded = codec::encode(self.codec, &object);
+        if encoded == bytes {
+            return Ok(false);
+        }
+        log::info!(
+            "recompressing {} in {:?} with {}",
+            id,
+            self.root,
+            self.codec
+        );
+        atomic::write(&path, &encoded)?;
+        Ok(true)
     }
 }
 
@@ -37,7 +93,7 @@ impl ObjectStore for DirectoryObjectStore {
         let subdir: &str = &s[0..2];
         let filename: &str = &s[2..];
         let path = self.root.join(format!("{}/{}", subdir, filename));
-        std::fs::try_exists(path)
+        std::fs::exists(path)
     }
 
     fn read(&self, id: ObjectId) -> Result<Option<Vec<u8>>, Self::Error> {
@@ -50,13 +106,17 @@ impl ObjectStore for DirectoryObjectStore {
             Ok(mut f) => {
                 let mut v = Vec::new();
                 f.read_to_end(&mut v)?;
-                return Ok(Some(v));
+                let object = codec::decode(v)?.1;
+                if self.verify {
+                    return Ok(Some(verify(id, object)?));
+                }
+                Ok(Some(object))
             }
             Err(err) => {
                 if err.kind() == ErrorKind::NotFound {
-                    return Ok(None);
+                    Ok(None)
                 } else {
-                    return Err(err);
+                    Err(err)
                 }
             }
         }
@@ -68,20 +128,182 @@ impl ObjectStore for DirectoryObjectStore {
         let s: String = format!("{}", id);
         let subdir: &str = &s[0..2];
         let filename: &str = &s[2..];
-        let subdir_path = self.root.join(format!("{}", subdir));
-        let path = subdir_path.join(format!("{}", filename));
-        if std::fs::try_exists(&path)? {
+        let subdir_path = self.root.join(subdir);
+        let path = subdir_path.join(filename);
+        if std::fs::exists(&path)? {
             log::info!("{:?} already exists", path);
+            freshen(&path)?;
             return Ok(id);
         }
-        if !std::fs::try_exists(&subdir_path)? {
+        if !std::fs::exists(&subdir_path)? {
             log::info!("creating subdir path {:?} in {:?}", subdir_path, self.root);
             std::fs::create_dir(&subdir_path)?;
         }
-        let mut f = File::options().create(true).write(true).open(path)?;
-        f.write(object)?;
+        atomic::write(&path, &codec::encode(self.codec, object))?;
+        Ok(id)
+    }
+
+    fn ids(&self) -> Ids<'_, Self::Error> {
+        let subdirs = match std::fs::read_dir(&self.root) {
+            Ok(subdirs) => subdirs,
+            Err(err) => return Box::new(iter::once(Err(err))),
+        };
+        Box::new(subdirs.flat_map(move |subdir| -> Ids<'_, Self::Error> {
+            let subdir = match subdir {
+                Ok(subdir) => subdir,
+                Err(err) => return Box::new(iter::once(Err(err))),
+            };
+            match subdir.file_type() {
+                Ok(file_type) if file_type.is_dir() => {}
+                Ok(_) => return Box::new(iter::empty()),
+                Err(err) => return Box::new(iter::once(Err(err))),
+            }
+            let files = match std::fs::read_dir(subdir.path()) {
+                Ok(files) => files,
+                Err(err) => return Box::new(iter::once(Err(err))),
+            };
+            let prefix = subdir.file_name().to_string_lossy().into_owned();
+            Box::new(files.filter_map(move |file| {
+                let file = match file {
+                    Ok(file) => file,
+                    Err(err) => return Some(Err(err)),
+                };
+                if file.file_name().to_string_lossy().starts_with('.') {
+                    return None;
+                }
+                let name = format!("{}{}", prefix, file.file_name().to_string_lossy());
+                match name.parse() {
+                    Ok(id) => Some(Ok(id)),
+                    Err(_) => {
+                        log::warn!("ignoring {:?} in {:?}", file.path(), self.root);
+                        None
+                    }
+                }
+            }))
+        }))
+    }
+
+    fn remove(&mut self, id: ObjectId) -> Result<(), Self::Error> {
+        log::info!("removing {} from {:?}", id, self.root);
+        let s: String = format!("{}", id);
+        let path = self.root.join(&s[0..2]).join(&s[2..]);
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn modified(&self, id: ObjectId) -> Result<Option<SystemTime>, Self::Error> {
+        let s: String = format!("{}", id);
+        let path = self.root.join(&s[0..2]).join(&s[2..]);
+        match std::fs::metadata(path) {
+            Ok(metadata) => Ok(Some(metadata.modified()?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Objects which are not compressed are as large as their files,
+    /// less the header.
+    fn size_of(&self, id: ObjectId) -> Result<Option<u64>, Self::Error> {
+        let s: String = format!("{}", id);
+        let path = self.root.join(&s[0..2]).join(&s[2..]);
+        let mut f = match File::options().read(true).open(path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let header = codec::header(Codec::None);
+        let mut bytes = Vec::new();
+        (&mut f).take(header.len() as u64).read_to_end(&mut bytes)?;
+        if bytes == header {
+            return Ok(Some(f.metadata()?.len() - header.len() as u64));
+        }
+        f.read_to_end(&mut bytes)?;
+        Ok(Some(codec::decode(bytes)?.1.len() as u64))
+    }
+
+    fn insert_stream(
+        &mut self,
+        reader: &mut dyn Read,
+    ) -> Result<ObjectId, StreamError<Self::Error>> {
+        let mut buffer = Vec::new();
+        reader
+            .take(STREAM_BUFFER_SIZE as u64)
+            .read_to_end(&mut buffer)
+            .map_err(StreamError::Read)?;
+        if buffer.len() < STREAM_BUFFER_SIZE {
+            return self.insert(&buffer).map_err(StreamError::Store);
+        }
+
+        log::info!("streaming an object into {:?}", self.root);
+        let mut f = AtomicFile::new(&self.root).map_err(StreamError::Store)?;
+        f.write_all(&codec::header(Codec::None))
+            .map_err(StreamError::Store)?;
+        let mut hasher = blake3::Hasher::new();
+        while !buffer.is_empty() {
+            hasher.update(&buffer);
+            f.write_all(&buffer).map_err(StreamError::Store)?;
+            buffer.clear();
+            reader
+                .take(STREAM_BUFFER_SIZE as u64)
+                .read_to_end(&mut buffer)
+                .map_err(StreamError::Read)?;
+        }
+        let id = ObjectId::from_bytes(*hasher.finalize().as_bytes());
+
+        let s: String = format!("{}", id);
+        let subdir_path = self.root.join(&s[0..2]);
+        let path = subdir_path.join(&s[2..]);
+        if std::fs::exists(&path).map_err(StreamError::Store)? {
+            log::info!("{:?} already exists", path);
+            freshen(&path).map_err(StreamError::Store)?;
+            return Ok(id);
+        }
+        if !std::fs::exists(&subdir_path).map_err(StreamError::Store)? {
+            std::fs::create_dir(&subdir_path).map_err(StreamError::Store)?;
+        }
+        f.commit(&path).map_err(StreamError::Store)?;
         Ok(id)
     }
+
+    fn read_stream(&self, id: ObjectId) -> Result<Option<Box<dyn Read + '_>>, Self::Error> {
+        log::info!("streaming {} from {:?}", id, self.root);
+        let s: String = format!("{}", id);
+        let path = self.root.join(&s[0..2]).join(&s[2..]);
+        let mut f = match File::options().read(true).open(path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut header = Vec::new();
+        (&mut f)
+            .take(codec::header(Codec::None).len() as u64)
+            .read_to_end(&mut header)?;
+        if header == codec::header(Codec::None) {
+            if self.verify {
+                return Ok(Some(Box::new(VerifyingReader::new(id, f))));
+            }
+            return Ok(Some(Box::new(f)));
+        }
+        // Compressed objects and those written before objects had a header
+        // are small enough to decode in memory.
+        f.read_to_end(&mut header)?;
+        let mut object = codec::decode(header)?.1;
+        if self.verify {
+            object = verify(id, object)?;
+        }
+        Ok(Some(Box::new(Cursor::new(object))))
+    }
+}
+
+/// Mark an object which was inserted again as new, so that garbage
+/// collection does not remove it before whatever refers to it is written.
+fn freshen(path: &Path) -> Result<(), std::io::Error> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
 }
 
 #[test]
@@ -92,4 +314,66 @@ fn test_directory_object_store() {
     let b: &[u8] = b"hello, world";
     assert!(store.has(b.into()).unwrap());
     assert_eq!(store.read(b.into()).unwrap(), Some(Vec::from(b)));
+
+    let large: Vec<u8> = (0..3 * STREAM_BUFFER_SIZE)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let id = store.insert_stream(&mut large.as_slice()).unwrap();
+    assert_eq!(id, ObjectId::from(&large));
+    let mut streamed = Vec::new();
+    store
+        .read_stream(id)
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut streamed)
+        .unwrap();
+    assert_eq!(streamed, large);
+    assert_eq!(store.size_of(id).unwrap(), Some(large.len() as u64));
+    assert_eq!(store.read(id).unwrap(), Some(large));
+    assert!(store
+        .read_stream(ObjectId::from(&Vec::new()))
+        .unwrap()
+        .is_none());
+
+    let tempdir = tempfile::tempdir().unwrap();
+    super::test_object_store(&mut DirectoryObjectStore::new(tempdir.path().into()).unwrap());
+}
+
+#[test]
+fn test_recompress() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let object = "hello, world\n".repeat(100);
+    let id = ObjectId::from(object.as_bytes());
+    let s = format!("{}", id);
+    let path = tempdir.path().join(&s[0..2]).join(&s[2..]);
+    // An object written before objects had a header.
+    std::fs::create_dir(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, &object).unwrap();
+    let mut store = DirectoryObjectStore::new(tempdir.path().into()).unwrap();
+    assert_eq!(store.read(id).unwrap(), Some(object.clone().into_bytes()));
+    assert!(store.recompress(id).unwrap());
+    assert!(!store.recompress(id).unwrap());
+    assert!(std::fs::metadata(&path).unwrap().len() < object.len() as u64 / 4);
+    assert_eq!(store.read(id).unwrap(), Some(object.clone().into_bytes()));
+    let mut store = store.with_codec(Codec::None);
+    assert!(store.recompress(id).unwrap());
+    assert_eq!(store.read(id).unwrap(), Some(object.into_bytes()));
+}
+
+#[test]
+fn test_verify() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut store = DirectoryObjectStore::new(tempdir.path().into()).unwrap();
+    let id = store.insert(b"hello, world").unwrap();
+    let s = format!("{}", id);
+    std::fs::write(tempdir.path().join(&s[0..2]).join(&s[2..]), "corrupted").unwrap();
+    assert_eq!(store.read(id).unwrap(), Some(b"corrupted".to_vec()));
+    let store = store.with_verify(true);
+    let err = store.read(id).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    // Small objects are checked on opening, streamed ones on reaching the end.
+    assert!(match store.read_stream(id) {
+        Ok(reader) => reader.unwrap().read_to_end(&mut Vec::new()).is_err(),
+        Err(_) => true,
+    });
 }
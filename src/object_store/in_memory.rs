@@ -5,11 +5,11 @@ use std::{
 
 use crate::object_id::ObjectId;
 
-use super::ObjectStore;
+use super::{Ids, ObjectStore};
 
 /// An ephemeral [`ObjectStore`] stored in memory using a
 /// [`BTreeMap`].
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct InMemoryObjectStore {
     objects: BTreeMap<ObjectId, Vec<u8>>,
 }
@@ -47,6 +47,15 @@ impl ObjectStore for InMemoryObjectStore {
             Entry::Occupied(_o) => Ok(id),
         }
     }
+
+    fn ids(&self) -> Ids<'_, Self::Error> {
+        Box::new(self.objects.keys().copied().map(Ok))
+    }
+
+    fn remove(&mut self, id: ObjectId) -> Result<(), Self::Error> {
+        self.objects.remove(&id);
+        Ok(())
+    }
 }
 
 #[test]
@@ -56,4 +65,5 @@ fn test_in_memory_object_store() {
     let b: &[u8] = b"hello, world";
     assert!(store.has(b.into()).unwrap());
     assert_eq!(store.read(b.into()).unwrap(), Some(Vec::from(b)));
+    super::test_object_store(&mut InMemoryObjectStore::new());
 }
@@ -0,0 +1,117 @@
+use std::{io::Read, time::SystemTime};
+
+use crate::object_id::ObjectId;
+
+use super::{Ids, ObjectStore, StreamError};
+
+/// An [`ObjectStore`] which looks objects up in `upper` and then in
+/// `lower`, and inserts objects into `upper`.
+///
+/// A repository uses this to keep new objects loose while reading the
+/// older ones out of packs. Objects `lower` already has are written into
+/// `upper` again, so that reusing one records a fresh modification time
+/// and [`gc`](crate::gc) does not sweep it from under a snapshot being
+/// taken.
+#[derive(Debug, Clone)]
+pub struct LayeredObjectStore<Upper, Lower> {
+    upper: Upper,
+    lower: Lower,
+}
+
+impl<Upper, Lower> LayeredObjectStore<Upper, Lower> {
+    pub fn new(upper: Upper, lower: Lower) -> Self {
+        Self { upper, lower }
+    }
+
+    pub fn upper(&mut self) -> &mut Upper {
+        &mut self.upper
+    }
+
+    pub fn lower(&mut self) -> &mut Lower {
+        &mut self.lower
+    }
+}
+
+impl<Upper, Lower> ObjectStore for LayeredObjectStore<Upper, Lower>
+where
+    Upper: ObjectStore,
+    Lower: ObjectStore<Error = Upper::Error>,
+{
+    type Error = Upper::Error;
+
+    fn has(&self, id: ObjectId) -> Result<bool, Self::Error> {
+        Ok(self.upper.has(id)? || self.lower.has(id)?)
+    }
+
+    fn read(&self, id: ObjectId) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.upper.read(id)? {
+            Some(object) => Ok(Some(object)),
+            None => self.lower.read(id),
+        }
+    }
+
+    fn insert(&mut self, object: &[u8]) -> Result<ObjectId, Self::Error> {
+        self.upper.insert(object)
+    }
+
+    /// Objects in both layers are only listed once.
+    fn ids(&self) -> Ids<'_, Self::Error> {
+        let lower = self.lower.ids().filter_map(|id| match id {
+            Ok(id) => match self.upper.has(id) {
+                Ok(true) => None,
+                Ok(false) => Some(Ok(id)),
+                Err(err) => Some(Err(err)),
+            },
+            Err(err) => Some(Err(err)),
+        });
+        Box::new(self.upper.ids().chain(lower))
+    }
+
+    fn remove(&mut self, id: ObjectId) -> Result<(), Self::Error> {
+        self.upper.remove(id)?;
+        self.lower.remove(id)
+    }
+
+    fn modified(&self, id: ObjectId) -> Result<Option<SystemTime>, Self::Error> {
+        if self.upper.has(id)? {
+            return self.upper.modified(id);
+        }
+        self.lower.modified(id)
+    }
+
+    fn insert_stream(
+        &mut self,
+        reader: &mut dyn Read,
+    ) -> Result<ObjectId, StreamError<Self::Error>> {
+        self.upper.insert_stream(reader)
+    }
+
+    fn read_stream(&self, id: ObjectId) -> Result<Option<Box<dyn Read + '_>>, Self::Error> {
+        match self.upper.read_stream(id)? {
+            Some(reader) => Ok(Some(reader)),
+            None => self.lower.read_stream(id),
+        }
+    }
+}
+
+#[test]
+fn test_layered_object_store() {
+    use super::in_memory::InMemoryObjectStore;
+
+    let mut lower = InMemoryObjectStore::new();
+    let old = lower.insert(b"old").unwrap();
+    let mut store = LayeredObjectStore::new(InMemoryObjectStore::new(), lower);
+    let new = store.insert(b"new").unwrap();
+    store.insert(b"old").unwrap();
+    assert_eq!(store.read(old).unwrap(), Some(b"old".to_vec()));
+    assert_eq!(store.read(new).unwrap(), Some(b"new".to_vec()));
+    assert!(store.upper().has(old).unwrap());
+    assert!(!store.lower().has(new).unwrap());
+    assert_eq!(store.ids().count(), 2);
+    store.remove(old).unwrap();
+    assert!(!store.has(old).unwrap());
+    super::test_object_store(&mut LayeredObjectStore::new(
+        InMemoryObjectStore::new(),
+        InMemoryObjectStore::new(),
+    ));
+}
@@ -0,0 +1,703 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{create_dir_all, exists, read_dir, File},
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{atomic, delta, object_id::ObjectId};
+
+use super::{verify::verify, Ids, ObjectStore};
+
+/// Packs written before deltas were supported, holding only whole objects.
+const PACK_MAGIC_V1: &[u8; 8] = b"RVPACK01";
+const PACK_MAGIC: &[u8; 8] = b"RVPACK02";
+const INDEX_MAGIC: &[u8; 8] = b"RVIDX001";
+/// The size of an index entry: the [`ObjectId`], the offset of the object
+/// in the pack and its length.
+const INDEX_ENTRY_SIZE: usize = 32 + 8 + 8;
+/// Once this many bytes are waiting to be written, they are written out
+/// as a pack.
+const MAX_PENDING_SIZE: usize = 64 * 1024 * 1024;
+/// The longest chain of deltas which has to be applied to read an object.
+pub const MAX_DELTA_DEPTH: u8 = 16;
+
+/// A persistent [`ObjectStore`] which stores many objects in each pack
+/// file, so that a large store does not need a file per object.
+///
+/// Each pack `pack-<id>.pack` is a header followed by every object, each
+/// preceded by its [`ObjectId`] and length. Next to it `pack-<id>.idx`
+/// lists the [`ObjectId`]s in the pack in sorted order with the offset
+/// and length of each object, so that an object is found with a binary
+/// search. Packs are never modified once written.
+///
+/// An object is either stored whole or, when [`PackObjectStore::suggest_base`]
+/// named a similar object and the result is much smaller, as a
+/// [`delta`] against that base. Following bases back to a whole object
+/// takes at most [`MAX_DELTA_DEPTH`] steps.
+///
+/// Inserted objects are kept in memory until [`PackObjectStore::flush`]
+/// writes them out as a new pack, which also happens once enough of them
+/// have been inserted and when the store is dropped. Flushing also
+/// replaces each pack holding removed objects with one without them.
+#[derive(Debug)]
+pub struct PackObjectStore {
+    root: PathBuf,
+    packs: Vec<Pack>,
+    pending: BTreeMap<ObjectId, Vec<u8>>,
+    pending_size: usize,
+    bases: BTreeMap<ObjectId, ObjectId>,
+    removed: BTreeSet<ObjectId>,
+    verify: bool,
+}
+
+#[derive(Debug)]
+struct Pack {
+    path: PathBuf,
+    /// Whether objects in this pack start with their delta depth.
+    deltas: bool,
+    index: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    id: ObjectId,
+    offset: u64,
+    length: u64,
+}
+
+/// An object as it is stored in a pack.
+enum Stored {
+    Whole(Vec<u8>),
+    Delta {
+        depth: u8,
+        base: ObjectId,
+        delta: Vec<u8>,
+    },
+}
+
+impl Stored {
+    fn depth(&self) -> u8 {
+        match self {
+            Stored::Whole(_) => 0,
+            Stored::Delta { depth, .. } => *depth,
+        }
+    }
+
+    /// The depth, followed by the base for deltas, followed by the object
+    /// or the delta.
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Stored::Whole(object) => [&[0], &object[..]].concat(),
+            Stored::Delta { depth, base, delta } => {
+                [&[*depth], &base.as_bytes()[..], &delta[..]].concat()
+            }
+        }
+    }
+
+    fn decode(mut bytes: Vec<u8>) -> Option<Self> {
+        match *bytes.first()? {
+            0 => {
+                bytes.remove(0);
+                Some(Stored::Whole(bytes))
+            }
+            depth => Some(Stored::Delta {
+                depth,
+                base: ObjectId::from_bytes(bytes.get(1..33)?.try_into().unwrap()),
+                delta: bytes.split_off(33),
+            }),
+        }
+    }
+}
+
+impl PackObjectStore {
+    /// Open the packs in the given directory, creating it if it does not
+    /// exist yet.
+    pub fn new(root: PathBuf) -> Result<Self, std::io::Error> {
+        if !exists(&root)? {
+            log::info!("creating pack store root: {:?}", root);
+            create_dir_all(&root)?;
+        }
+        let mut packs = Vec::new();
+        for entry in read_dir(&root)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "idx") {
+                let pack = path.with_extension("pack");
+                if !exists(&pack)? {
+                    log::warn!("ignoring {:?} without a pack", path);
+                    continue;
+                }
+                let mut magic = [0; 8];
+                let mut file = File::options().read(true).open(&pack)?;
+                file.read_exact(&mut magic)?;
+                let deltas = match &magic {
+                    PACK_MAGIC => true,
+                    PACK_MAGIC_V1 => false,
+                    _ => return Err(invalid(&pack)),
+                };
+                packs.push(Pack {
+                    index: read_index(&path, file.metadata()?.len())?,
+                    deltas,
+                    path: pack,
+                });
+            }
+        }
+        Ok(Self {
+            root,
+            packs,
+            pending: BTreeMap::new(),
+            pending_size: 0,
+            bases: BTreeMap::new(),
+            removed: BTreeSet::new(),
+            verify: false,
+        })
+    }
+
+    /// Check that every object read hashes to its [`ObjectId`], failing
+    /// with [`ErrorKind::InvalidData`] if it does not.
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Suggest storing the object `id`, which has been inserted but not
+    /// yet flushed, as a delta against `base`, such as an earlier version
+    /// of the same file. The suggestion is dropped if `base` is not in this
+    /// store or the delta would not save much.
+    pub fn suggest_base(&mut self, id: ObjectId, base: ObjectId) {
+        if id != base && self.pending.contains_key(&id) && !self.removed.contains(&base) {
+            self.bases.insert(id, base);
+        }
+    }
+
+    /// Write the objects inserted since the last flush out as a new pack,
+    /// and rewrite the packs holding objects removed since then.
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        if !self.pending.is_empty() {
+            let mut encoded = BTreeMap::new();
+            for id in self.pending.keys() {
+                self.encode_pending(*id, &mut encoded)?;
+            }
+            let records = encoded
+                .into_iter()
+                .map(|(id, stored)| (id, stored.unwrap().encode()))
+                .collect();
+            let pack = self.write_pack(records)?;
+            self.packs.push(pack);
+            self.pending.clear();
+            self.pending_size = 0;
+            self.bases.clear();
+        }
+        if !self.removed.is_empty() {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Replace every pack holding a removed object with one holding the
+    /// rest of its objects. A delta against a removed object is stored
+    /// whole instead, which means rewriting the pack holding it too, while
+    /// every other object is copied as it is, so no delta chain gets any
+    /// longer.
+    ///
+    /// The old packs are only deleted once all of the new ones have been
+    /// written, since objects being stored whole are read through them.
+    fn prune(&mut self) -> Result<(), std::io::Error> {
+        let mut rewrite = Vec::new();
+        for (i, pack) in self.packs.iter().enumerate() {
+            let holds_removed = pack
+                .index
+                .iter()
+                .any(|entry| self.removed.contains(&entry.id));
+            if holds_removed || (pack.deltas && self.has_removed_base(pack)?) {
+                rewrite.push(i);
+            }
+        }
+
+        let mut new_packs = Vec::new();
+        for &i in &rewrite {
+            let pack = &self.packs[i];
+            let bytes = std::fs::read(&pack.path)?;
+            let mut records = Vec::new();
+            for entry in &pack.index {
+                if self.removed.contains(&entry.id) {
+                    continue;
+                }
+                let record = record(&bytes, entry).ok_or_else(|| invalid(&pack.path))?;
+                let record = if !pack.deltas {
+                    Stored::Whole(record.to_vec()).encode()
+                } else if base(record).is_some_and(|base| self.removed.contains(&base)) {
+                    let object = self.resolve(entry.id)?.ok_or_else(|| invalid(&pack.path))?;
+                    Stored::Whole(object).encode()
+                } else {
+                    record.to_vec()
+                };
+                records.push((entry.id, record));
+            }
+            if !records.is_empty() {
+                // Keep the age of the objects, which garbage collection
+                // goes by.
+                let modified = std::fs::metadata(&pack.path)?.modified()?;
+                let new_pack = self.write_pack(records)?;
+                File::options()
+                    .write(true)
+                    .open(&new_pack.path)?
+                    .set_modified(modified)?;
+                new_packs.push(new_pack);
+            }
+        }
+
+        for &i in rewrite.iter().rev() {
+            let pack = self.packs.remove(i);
+            log::info!("removing {:?}", pack.path);
+            // The index goes first, so that a pack is never picked up
+            // without all of its objects.
+            std::fs::remove_file(pack.path.with_extension("idx"))?;
+            std::fs::remove_file(&pack.path)?;
+        }
+        self.packs.extend(new_packs);
+        self.removed.clear();
+        Ok(())
+    }
+
+    /// Whether any object in the pack is a delta against a removed object.
+    fn has_removed_base(&self, pack: &Pack) -> Result<bool, std::io::Error> {
+        let bytes = std::fs::read(&pack.path)?;
+        for entry in &pack.index {
+            let record = record(&bytes, entry).ok_or_else(|| invalid(&pack.path))?;
+            if base(record).is_some_and(|base| self.removed.contains(&base)) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Write out a pack of the encoded objects, which are sorted by their
+    /// [`ObjectId`].
+    fn write_pack(&self, records: Vec<(ObjectId, Vec<u8>)>) -> Result<Pack, std::io::Error> {
+        let mut pack = Vec::from(&PACK_MAGIC[..]);
+        let mut index = Vec::with_capacity(records.len());
+        for (id, stored) in records {
+            pack.extend_from_slice(id.as_bytes());
+            pack.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+            index.push(IndexEntry {
+                id,
+                offset: pack.len() as u64,
+                length: stored.len() as u64,
+            });
+            pack.extend_from_slice(&stored);
+        }
+
+        let name = format!("pack-{}", ObjectId::from(&pack[..]));
+        let path = self.root.join(&name).with_extension("pack");
+        log::info!("writing {} objects to {:?}", index.len(), path);
+        atomic::write(&path, &pack)?;
+        // The index is written last, since the pack is only picked up once
+        // its index exists.
+        let mut index_bytes = Vec::from(&INDEX_MAGIC[..]);
+        index_bytes.extend_from_slice(&(index.len() as u64).to_le_bytes());
+        for entry in &index {
+            index_bytes.extend_from_slice(entry.id.as_bytes());
+            index_bytes.extend_from_slice(&entry.offset.to_le_bytes());
+            index_bytes.extend_from_slice(&entry.length.to_le_bytes());
+        }
+        atomic::write(&path.with_extension("idx"), &index_bytes)?;
+
+        Ok(Pack {
+            path,
+            deltas: true,
+            index,
+        })
+    }
+
+    /// Decide how to store the pending object, deciding for its suggested
+    /// base first if that is pending too, and return its delta depth.
+    ///
+    /// An object being decided on is `None` in `encoded`, so a cycle of
+    /// suggestions ends with an object stored whole.
+    fn encode_pending(
+        &self,
+        id: ObjectId,
+        encoded: &mut BTreeMap<ObjectId, Option<Stored>>,
+    ) -> Result<Option<u8>, std::io::Error> {
+        if let Some(stored) = encoded.get(&id) {
+            return Ok(stored.as_ref().map(Stored::depth));
+        }
+        encoded.insert(id, None);
+        let object = &self.pending[&id];
+        let mut stored = Stored::Whole(object.clone());
+        if let Some(&base) = self
+            .bases
+            .get(&id)
+            .filter(|base| !self.removed.contains(base))
+        {
+            let base_depth = if self.pending.contains_key(&base) {
+                self.encode_pending(base, encoded)?
+            } else {
+                self.read_stored(base)?.as_ref().map(Stored::depth)
+            };
+            if let Some(base_depth) = base_depth.filter(|depth| *depth < MAX_DELTA_DEPTH) {
+                let base_object = self.resolve(base)?.ok_or_else(|| invalid(&self.root))?;
+                let delta = delta::encode(&base_object, object);
+                if delta.len() + 32 < object.len() / 2 {
+                    stored = Stored::Delta {
+                        depth: base_depth + 1,
+                        base,
+                        delta,
+                    };
+                }
+            }
+        }
+        let depth = stored.depth();
+        encoded.insert(id, Some(stored));
+        Ok(Some(depth))
+    }
+
+    /// The object as it is stored, without resolving deltas.
+    fn read_stored(&self, id: ObjectId) -> Result<Option<Stored>, std::io::Error> {
+        if let Some(object) = self.pending.get(&id) {
+            return Ok(Some(Stored::Whole(object.clone())));
+        }
+        let Some((pack, entry)) = self.find(id) else {
+            return Ok(None);
+        };
+        let mut f = File::options().read(true).open(&pack.path)?;
+        f.seek(SeekFrom::Start(entry.offset))?;
+        let mut bytes = vec![0; entry.length as usize];
+        f.read_exact(&mut bytes)?;
+        if !pack.deltas {
+            return Ok(Some(Stored::Whole(bytes)));
+        }
+        Stored::decode(bytes)
+            .map(Some)
+            .ok_or_else(|| invalid(&pack.path))
+    }
+
+    /// The object, following its deltas back to a whole object, whether or
+    /// not it has been removed.
+    fn resolve(&self, id: ObjectId) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut deltas = Vec::new();
+        let mut next = id;
+        let mut object = loop {
+            match self.read_stored(next)? {
+                None if deltas.is_empty() => return Ok(None),
+                None => return Err(invalid(&self.root)),
+                Some(Stored::Whole(object)) => break object,
+                Some(Stored::Delta { base, delta, .. }) => {
+                    if deltas.len() >= MAX_DELTA_DEPTH as usize {
+                        return Err(invalid(&self.root));
+                    }
+                    deltas.push(delta);
+                    next = base;
+                }
+            }
+        };
+        for delta in deltas.iter().rev() {
+            object = delta::apply(&object, delta).ok_or_else(|| invalid(&self.root))?;
+        }
+        Ok(Some(object))
+    }
+
+    fn find(&self, id: ObjectId) -> Option<(&Pack, IndexEntry)> {
+        self.packs.iter().find_map(|pack| {
+            pack.index
+                .binary_search_by(|entry| entry.id.cmp(&id))
+                .ok()
+                .map(|i| (pack, pack.index[i]))
+        })
+    }
+}
+
+impl Drop for PackObjectStore {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("failed to write pack to {:?}: {}", self.root, err);
+        }
+    }
+}
+
+impl ObjectStore for PackObjectStore {
+    type Error = std::io::Error;
+
+    fn has(&self, id: ObjectId) -> Result<bool, Self::Error> {
+        log::info!("checking whether {} is contained in {:?}", id, self.root);
+        Ok(self.pending.contains_key(&id)
+            || (self.find(id).is_some() && !self.removed.contains(&id)))
+    }
+
+    fn read(&self, id: ObjectId) -> Result<Option<Vec<u8>>, Self::Error> {
+        log::info!("reading {} from {:?}", id, self.root);
+        if self.removed.contains(&id) {
+            return Ok(None);
+        }
+        let Some(object) = self.resolve(id)? else {
+            return Ok(None);
+        };
+        if self.verify {
+            return Ok(Some(verify(id, object)?));
+        }
+        Ok(Some(object))
+    }
+
+    fn insert(&mut self, object: &[u8]) -> Result<ObjectId, Self::Error> {
+        let id: ObjectId = object.into();
+        log::info!("inserting {} into {:?}", id, self.root);
+        // An object removed since the last flush is still in its pack.
+        if self.removed.remove(&id) || self.has(id)? {
+            return Ok(id);
+        }
+        self.pending.insert(id, object.to_vec());
+        self.pending_size += object.len();
+        if self.pending_size >= MAX_PENDING_SIZE {
+            self.flush()?;
+        }
+        Ok(id)
+    }
+
+    fn ids(&self) -> Ids<'_, Self::Error> {
+        let packed = self
+            .packs
+            .iter()
+            .flat_map(|pack| pack.index.iter().map(|entry| entry.id))
+            .filter(|id| !self.removed.contains(id));
+        Box::new(self.pending.keys().copied().chain(packed).map(Ok))
+    }
+
+    fn remove(&mut self, id: ObjectId) -> Result<(), Self::Error> {
+        log::info!("removing {} from {:?}", id, self.root);
+        if let Some(object) = self.pending.remove(&id) {
+            self.pending_size -= object.len();
+            self.bases.remove(&id);
+        }
+        if self.find(id).is_some() {
+            self.removed.insert(id);
+        }
+        Ok(())
+    }
+
+    /// Whole objects are as large as their records, less the depth.
+    fn size_of(&self, id: ObjectId) -> Result<Option<u64>, Self::Error> {
+        if self.removed.contains(&id) {
+            return Ok(None);
+        }
+        if let Some(object) = self.pending.get(&id) {
+            return Ok(Some(object.len() as u64));
+        }
+        let Some((pack, entry)) = self.find(id) else {
+            return Ok(None);
+        };
+        if !pack.deltas {
+            return Ok(Some(entry.length));
+        }
+        let mut f = File::options().read(true).open(&pack.path)?;
+        f.seek(SeekFrom::Start(entry.offset))?;
+        let mut depth = [0];
+        f.read_exact(&mut depth)?;
+        if depth[0] == 0 {
+            return Ok(Some(entry.length - 1));
+        }
+        Ok(self.resolve(id)?.map(|object| object.len() as u64))
+    }
+
+    /// Objects are as old as the pack holding them.
+    fn modified(&self, id: ObjectId) -> Result<Option<SystemTime>, Self::Error> {
+        if self.pending.contains_key(&id) {
+            return Ok(Some(SystemTime::now()));
+        }
+        match self.find(id) {
+            Some((pack, _)) if !self.removed.contains(&id) => {
+                Ok(Some(std::fs::metadata(&pack.path)?.modified()?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The bytes of the object in the pack.
+fn record<'a>(pack: &'a [u8], entry: &IndexEntry) -> Option<&'a [u8]> {
+    pack.get(entry.offset as usize..(entry.offset + entry.length) as usize)
+}
+
+/// The base of an object stored as a delta.
+fn base(record: &[u8]) -> Option<ObjectId> {
+    match record.first()? {
+        0 => None,
+        _ => Some(ObjectId::from_bytes(record.get(1..33)?.try_into().unwrap())),
+    }
+}
+
+fn invalid(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::InvalidData,
+        format!("corrupt pack in {:?}", path),
+    )
+}
+
+/// Read the index at the path, checking that each of its entries lies
+/// within the pack after the magic, which is `pack_length` bytes long, so
+/// that reads never trust a corrupt length.
+fn read_index(path: &Path, pack_length: u64) -> Result<Vec<IndexEntry>, std::io::Error> {
+    let mut bytes = Vec::new();
+    File::options()
+        .read(true)
+        .open(path)?
+        .read_to_end(&mut bytes)?;
+    let (magic, rest) = bytes.split_at_checked(8).ok_or_else(|| invalid(path))?;
+    let (count, rest) = rest.split_at_checked(8).ok_or_else(|| invalid(path))?;
+    if magic != INDEX_MAGIC {
+        return Err(invalid(path));
+    }
+    let count = u64::from_le_bytes(count.try_into().unwrap());
+    if Some(rest.len() as u64) != count.checked_mul(INDEX_ENTRY_SIZE as u64) {
+        return Err(invalid(path));
+    }
+    let index: Vec<IndexEntry> = rest
+        .chunks_exact(INDEX_ENTRY_SIZE)
+        .map(|entry| IndexEntry {
+            id: ObjectId::from_bytes(entry[0..32].try_into().unwrap()),
+            offset: u64::from_le_bytes(entry[32..40].try_into().unwrap()),
+            length: u64::from_le_bytes(entry[40..48].try_into().unwrap()),
+        })
+        .collect();
+    if !index.is_sorted_by(|a, b| a.id < b.id) {
+        return Err(invalid(path));
+    }
+    let in_pack = |entry: &IndexEntry| {
+        entry.offset >= PACK_MAGIC.len() as u64
+            && entry
+                .offset
+                .checked_add(entry.length)
+                .is_some_and(|end| end <= pack_length)
+    };
+    if !index.iter().all(in_pack) {
+        return Err(invalid(path));
+    }
+    Ok(index)
+}
+
+#[test]
+fn test_pack_object_store() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut store = PackObjectStore::new(tempdir.path().into()).unwrap();
+    let a = store.insert(b"hello, world").unwrap();
+    assert_eq!(store.read(a).unwrap(), Some(b"hello, world".to_vec()));
+    store.flush().unwrap();
+    let b = store.insert(b"goodbye, world").unwrap();
+    let c = store.insert(b"").unwrap();
+    drop(store);
+
+    let store = PackObjectStore::new(tempdir.path().into()).unwrap();
+    assert_eq!(store.packs.len(), 2);
+    assert_eq!(store.read(a).unwrap(), Some(b"hello, world".to_vec()));
+    assert_eq!(store.read(b).unwrap(), Some(b"goodbye, world".to_vec()));
+    assert_eq!(store.read(c).unwrap(), Some(Vec::new()));
+    let missing: &[u8] = b"missing";
+    assert!(!store.has(missing.into()).unwrap());
+    assert_eq!(store.read(missing.into()).unwrap(), None);
+    assert_eq!(store.size_of(b).unwrap(), Some(14));
+    drop(store);
+
+    // An index entry running past the end of its pack is refused on
+    // opening rather than trusted on reading.
+    for entry in read_dir(tempdir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|extension| extension == "idx") {
+            let mut index = std::fs::read(&path).unwrap();
+            index[16 + 40..16 + 48].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+            std::fs::write(&path, index).unwrap();
+        }
+    }
+    assert_eq!(
+        PackObjectStore::new(tempdir.path().into())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidData
+    );
+
+    let tempdir = tempfile::tempdir().unwrap();
+    super::test_object_store(&mut PackObjectStore::new(tempdir.path().into()).unwrap());
+}
+
+#[test]
+fn test_pack_deltas() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut store = PackObjectStore::new(tempdir.path().into()).unwrap();
+    let mut versions = Vec::new();
+    let mut contents: Vec<u8> = (0..1000u32)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+    for i in 0..40 {
+        contents.extend_from_slice(format!("version {}\n", i).as_bytes());
+        let id = store.insert(&contents).unwrap();
+        if let Some(&(previous, _)) = versions.last() {
+            store.suggest_base(id, previous);
+        }
+        versions.push((id, contents.clone()));
+        // Split the history across packs.
+        if i == 20 {
+            store.flush().unwrap();
+        }
+    }
+    // A cycle of suggestions still leaves one of the objects whole.
+    store.suggest_base(versions[21].0, versions[39].0);
+    drop(store);
+
+    let store = PackObjectStore::new(tempdir.path().into()).unwrap();
+    let pack_size: u64 = store
+        .packs
+        .iter()
+        .map(|pack| std::fs::metadata(&pack.path).unwrap().len())
+        .sum();
+    assert!(pack_size < 6 * contents.len() as u64);
+    for (id, contents) in versions {
+        let stored = store.read_stored(id).unwrap().unwrap();
+        assert!(stored.depth() <= MAX_DELTA_DEPTH);
+        assert_eq!(store.read(id).unwrap(), Some(contents));
+    }
+}
+
+#[test]
+fn test_pack_remove() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut store = PackObjectStore::new(tempdir.path().into()).unwrap();
+    let mut versions = Vec::new();
+    let mut contents: Vec<u8> = (0..1000u32)
+        .flat_map(|i| format!("line {}\n", i).into_bytes())
+        .collect();
+    for i in 0..6 {
+        contents.extend_from_slice(format!("version {}\n", i).as_bytes());
+        let id = store.insert(&contents).unwrap();
+        if let Some(&(previous, _)) = versions.last() {
+            store.suggest_base(id, previous);
+        }
+        versions.push((id, contents.clone()));
+        if i == 2 {
+            store.flush().unwrap();
+        }
+    }
+    store.flush().unwrap();
+    let other = store.insert(b"other").unwrap();
+    store.flush().unwrap();
+    assert_eq!(store.packs.len(), 3);
+
+    // Removing the first version and one which later versions in another
+    // pack are deltas against rewrites both of those packs.
+    let (first, _) = versions.remove(0);
+    let (third, _) = versions.remove(1);
+    store.remove(first).unwrap();
+    store.remove(third).unwrap();
+    assert!(!store.has(first).unwrap());
+    assert_eq!(store.read(third).unwrap(), None);
+    assert_eq!(store.ids().count(), versions.len() + 1);
+    drop(store);
+
+    let store = PackObjectStore::new(tempdir.path().into()).unwrap();
+    assert_eq!(store.packs.len(), 3);
+    assert!(!store.has(first).unwrap());
+    assert!(!store.has(third).unwrap());
+    assert_eq!(store.read(other).unwrap(), Some(b"other".to_vec()));
+    for (id, contents) in versions {
+        assert_eq!(store.read(id).unwrap(), Some(contents));
+    }
+}
@@ -0,0 +1,64 @@
+use std::io::{ErrorKind, Read};
+
+use crate::object_id::ObjectId;
+
+/// The error for an object whose contents do not hash to its
+/// [`ObjectId`].
+pub fn corrupt(id: ObjectId) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, format!("object {} is corrupt", id))
+}
+
+/// Check that the contents read for the object hash to its [`ObjectId`].
+pub fn verify(id: ObjectId, object: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+    if ObjectId::from(&object) == id {
+        Ok(object)
+    } else {
+        Err(corrupt(id))
+    }
+}
+
+/// A reader which hashes what it reads, failing at the end if that does
+/// not hash to the [`ObjectId`] being read.
+pub struct VerifyingReader<R> {
+    reader: R,
+    hasher: blake3::Hasher,
+    id: ObjectId,
+}
+
+impl<R> VerifyingReader<R> {
+    pub fn new(id: ObjectId, reader: R) -> Self {
+        Self {
+            reader,
+            hasher: blake3::Hasher::new(),
+            id,
+        }
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if ObjectId::from_bytes(*self.hasher.finalize().as_bytes()) != self.id {
+                return Err(corrupt(self.id));
+            }
+        } else {
+            self.hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_verifying_reader() {
+    let id = ObjectId::from(&b"contents".to_vec());
+    let mut read = Vec::new();
+    VerifyingReader::new(id, &b"contents"[..])
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, b"contents");
+    assert!(VerifyingReader::new(id, &b"corrupted"[..])
+        .read_to_end(&mut Vec::new())
+        .is_err());
+    assert!(verify(id, b"corrupted".to_vec()).is_err());
+}
@@ -1,8 +1,13 @@
-use std::collections::BTreeSet;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use serde::{Deserialize, Serialize};
 
-use crate::object_id::ObjectId;
+use crate::{object_id::ObjectId, object_store::ObjectStore};
 
 /// A particular snapshot of a version.
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -11,6 +16,241 @@ pub struct SnapShot {
     pub message: String,
     /// The [`ObjectId`] of the directory structure.
     pub directory: ObjectId,
-    /// The previous [`SnapShot`]s' [`ObjectId`]s, if there were some.
-    pub previous: BTreeSet<ObjectId>,
+    /// The previous [`SnapShot`]s' [`ObjectId`]s, if there were some. The
+    /// first is the one which was on the same branch.
+    pub previous: Vec<ObjectId>,
+    /// Who made the changes in this snapshot, and when.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<Signature>,
+    /// Who took this snapshot, and when.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub committer: Option<Signature>,
+    /// Free-form key/value pairs attached to the snapshot.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trailers: BTreeMap<String, String>,
+}
+
+/// An identity together with the time it acted.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub time: Timestamp,
+}
+
+/// A point in time, along with the offset from UTC of the time zone it
+/// was recorded in.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch.
+    pub seconds: i64,
+    /// Minutes east of UTC.
+    pub offset: i32,
+}
+
+#[derive(Debug)]
+pub enum Error<Store: ObjectStore> {
+    ObjectMissing(ObjectId),
+    Store(Store::Error),
+    Serde(serde_json::Error),
+    Directory(crate::directory::Error<Store>),
+}
+
+impl SnapShot {
+    /// Read the [`SnapShot`] object with the given [`ObjectId`] out of the
+    /// store.
+    pub fn read<Store: ObjectStore>(store: &Store, id: ObjectId) -> Result<Self, Error<Store>> {
+        match store.read(id).map_err(Error::Store)? {
+            Some(v) => serde_json::from_slice(&v).map_err(Error::Serde),
+            None => Err(Error::ObjectMissing(id)),
+        }
+    }
+
+    /// The time this snapshot was taken, falling back to when its changes
+    /// were made.
+    pub fn time(&self) -> Option<Timestamp> {
+        self.committer
+            .as_ref()
+            .or(self.author.as_ref())
+            .map(|signature| signature.time)
+    }
+}
+
+impl Timestamp {
+    /// The current time in the local time zone.
+    pub fn now() -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        Timestamp {
+            seconds,
+            offset: local_offset(seconds),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn local_offset(seconds: i64) -> i32 {
+    let time = seconds as libc::time_t;
+    // SAFETY: `localtime_r` only writes into the `tm` we pass it, and an
+    // all-zero `tm` is a valid value to start from.
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            0
+        } else {
+            (tm.tm_gmtoff / 60) as i32
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn local_offset(_seconds: i64) -> i32 {
+    0
+}
+
+/// Displayed as the local date and time followed by the offset, as in
+/// `2023-05-01 14:03:12 +0200`.
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let local = self.seconds + self.offset as i64 * 60;
+        let days = local.div_euclid(86400);
+        let seconds = local.rem_euclid(86400);
+        // Civil date from days since the epoch, after Howard Hinnant's
+        // `civil_from_days`.
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}{:02}",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            sign,
+            self.offset.abs() / 60,
+            self.offset.abs() % 60
+        )
+    }
+}
+
+/// Parsed from seconds since the Unix epoch followed by an offset, as in
+/// `1682942592 +0200`.
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("expected '<seconds> <+hhmm>', got '{}'", s);
+        let (seconds, offset) = s.trim().split_once(' ').ok_or_else(bad)?;
+        let seconds: i64 = seconds.parse().map_err(|_| bad())?;
+        let (sign, offset) = match offset.split_at_checked(1) {
+            Some(("+", offset)) => (1, offset),
+            Some(("-", offset)) => (-1, offset),
+            _ => return Err(bad()),
+        };
+        if offset.len() != 4 || !offset.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(bad());
+        }
+        let hours: i32 = offset[..2].parse().map_err(|_| bad())?;
+        let minutes: i32 = offset[2..].parse().map_err(|_| bad())?;
+        Ok(Timestamp {
+            seconds,
+            offset: sign * (hours * 60 + minutes),
+        })
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <{}> {}", self.name, self.email, self.time)
+    }
+}
+
+/// Insert a [`SnapShot`] of a [`Directory`](crate::directory::Directory)
+/// holding the given files, taken at the given time if there is one,
+/// returning its [`ObjectId`].
+#[cfg(test)]
+pub(crate) fn test_snap<Store: ObjectStore>(
+    store: &mut Store,
+    message: &str,
+    files: &[(&str, &[u8])],
+    previous: Vec<ObjectId>,
+    time: Option<Timestamp>,
+) -> ObjectId
+where
+    Store::Error: std::fmt::Debug,
+{
+    use crate::directory::{Directory, DirectoryEntry, Mode};
+
+    let mut directory = Directory::default();
+    for (name, contents) in files {
+        let id = store.insert(contents).unwrap();
+        directory
+            .root
+            .insert(String::from(*name), DirectoryEntry::File(id, Mode::REGULAR));
+    }
+    let snapshot = SnapShot {
+        message: String::from(message),
+        directory: store
+            .insert(&serde_json::to_vec_pretty(&directory).unwrap())
+            .unwrap(),
+        previous,
+        author: None,
+        committer: time.map(|time| Signature {
+            name: String::from("someone"),
+            email: String::from("someone@example.com"),
+            time,
+        }),
+        trailers: BTreeMap::new(),
+    };
+    store
+        .insert(&serde_json::to_vec_pretty(&snapshot).unwrap())
+        .unwrap()
+}
+
+#[test]
+fn test_timestamp() {
+    let timestamp: Timestamp = "1682942592 +0200".parse().unwrap();
+    assert_eq!(
+        timestamp,
+        Timestamp {
+            seconds: 1682942592,
+            offset: 120
+        }
+    );
+    assert_eq!(timestamp.to_string(), "2023-05-01 14:03:12 +0200");
+    let timestamp: Timestamp = "0 -0930".parse().unwrap();
+    assert_eq!(timestamp.to_string(), "1969-12-31 14:30:00 -0930");
+    assert!("1682942592".parse::<Timestamp>().is_err());
+    assert!("1682942592 0200".parse::<Timestamp>().is_err());
+}
+
+#[test]
+fn test_snapshot_without_metadata() {
+    let json = r#"{
+        "message": "init",
+        "directory": "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        "previous": []
+    }"#;
+    let snapshot: SnapShot = serde_json::from_str(json).unwrap();
+    assert_eq!(snapshot.author, None);
+    assert_eq!(snapshot.committer, None);
+    assert!(snapshot.trailers.is_empty());
+    let round_trip: serde_json::Value =
+        serde_json::from_slice(&serde_json::to_vec(&snapshot).unwrap()).unwrap();
+    assert_eq!(
+        round_trip,
+        serde_json::from_str::<serde_json::Value>(json).unwrap()
+    );
 }